@@ -1,16 +1,75 @@
-use std::time::Duration;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use crate::{
     PlayCli,
+    breakpoint::Breakpoint,
     event::{AppEvent, Event, EventHandler, TICK_FPS},
+    keyframe::Keyframes,
     perf::FaultData,
     ui::Ui,
 };
+use color_eyre::eyre::{Report, bail};
 use ratatui::{
     DefaultTerminal,
     crossterm::event::{KeyCode, KeyEvent, KeyModifiers},
 };
 
+/// Where the `:` prompt moves playback to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeekTarget {
+    /// `event N`
+    Event(usize),
+    /// `time [[HH:]MM:]SS[.fraction]`
+    Time(Duration),
+    /// `+N%` or `-N%` of the trace's duration, from the current time.
+    Relative(f64),
+}
+
+impl FromStr for SeekTarget {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            if !percent.starts_with(['+', '-']) {
+                bail!("relative seeks must start with + or -");
+            }
+            return Ok(SeekTarget::Relative(percent.parse::<f64>()? / 100.0));
+        }
+        match s.split_once(' ') {
+            Some(("event", event)) => Ok(SeekTarget::Event(event.trim().parse()?)),
+            Some(("time", time)) => Ok(SeekTarget::Time(parse_time(time.trim())?)),
+            _ => bail!("expected `event N', `time HH:MM:SS.fff' or `+N%'"),
+        }
+    }
+}
+
+/// Parse `[[HH:]MM:]SS[.fraction]`.
+fn parse_time(s: &str) -> color_eyre::Result<Duration> {
+    let mut secs = 0;
+    let mut parts = s.rsplit(':');
+    let last = parts.next().unwrap_or_default();
+    for (i, part) in parts.enumerate() {
+        if i > 1 {
+            bail!("too many `:' in time");
+        }
+        secs += part.parse::<u64>()? * 60u64.pow(i as u32 + 1);
+    }
+    let (whole, fraction) = last.split_once('.').unwrap_or((last, ""));
+    if fraction.len() > 9 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        bail!("invalid fraction of a second `{}'", fraction);
+    }
+    secs += whole.parse::<u64>()?;
+    let nanos = format!("{:0<9}", fraction).parse::<u32>()?;
+    Ok(Duration::new(secs, nanos))
+}
+
+/// How long to spend taking snapshots on each tick.
+const INDEX_BUDGET: Duration = Duration::from_millis(10);
+
 /// Application.
 #[derive(Debug)]
 pub struct App {
@@ -19,15 +78,23 @@ pub struct App {
     pub ui: Ui,
     pub data: FaultData,
     pub cli: PlayCli,
+    pub keyframes: Keyframes,
 }
 
 impl App {
     /// Constructs a new instance of [`App`].
     pub fn new(cli: PlayCli, data: FaultData) -> Self {
+        Self::with_events(cli, data, EventHandler::new())
+    }
+
+    pub fn with_events(cli: PlayCli, data: FaultData, events: EventHandler) -> Self {
+        let ui = Ui::new(&cli, &data);
+        let num_events = data.records.slice().len();
         Self {
             running: true,
-            events: EventHandler::new(),
-            ui: Ui::new(&cli, &data),
+            events,
+            keyframes: Keyframes::new(num_events),
+            ui,
             data,
             cli,
         }
@@ -45,11 +112,23 @@ impl App {
 
     pub fn handle_events(&mut self) -> color_eyre::Result<()> {
         match self.events.next()? {
-            Event::Tick => self.tick(),
-            Event::Crossterm(event) => match event {
-                crossterm::event::Event::Key(key_event) => self.handle_key_event(key_event)?,
-                _ => {}
-            },
+            Event::Tick => {
+                self.tick();
+                self.index();
+            }
+            Event::Crossterm(event) => {
+                if let crossterm::event::Event::Key(key_event) = event {
+                    self.handle_key_event(key_event)?
+                }
+            }
+            Event::App(
+                app_event @ (AppEvent::MoveUp
+                | AppEvent::MoveDown
+                | AppEvent::PageUp
+                | AppEvent::PageDown
+                | AppEvent::Enter
+                | AppEvent::Esc),
+            ) if self.ui.event_log.is_frozen() => self.handle_event_log(app_event),
             Event::App(app_event) => match app_event {
                 AppEvent::Increment => self.increment_counter(1),
                 AppEvent::Decrement => self.decrement_counter(),
@@ -57,23 +136,66 @@ impl App {
                 AppEvent::Quit => self.quit(),
                 AppEvent::MoveUp => self.ui.fault_vis.move_highlight(true),
                 AppEvent::MoveDown => self.ui.fault_vis.move_highlight(false),
-                AppEvent::Enter => self.ui.fault_vis.select(),
+                AppEvent::PageUp => self.ui.fault_vis.page_highlight(true),
+                AppEvent::PageDown => self.ui.fault_vis.page_highlight(false),
+                AppEvent::Enter => {
+                    if self.ui.fault_vis.select() {
+                        self.redraw();
+                    }
+                }
                 AppEvent::Esc => {
-                    if !self.ui.fault_vis.deselect() {
+                    if self.ui.fault_vis.page_grid.take().is_some() {
+                        // The views underneath kept up, so there's nothing to replay.
+                    } else if !self.ui.fault_vis.deselect() {
                         self.quit();
+                    } else if self.ui.fault_vis.single_file.is_some() {
+                        self.redraw();
                     }
                 }
                 AppEvent::Char(c) => match c {
                     'b' => self.ui.fault_vis.toggle_break(),
                     'l' => self.ui.status.looping = !self.ui.status.looping,
-                    'r' => self.cli.play_speed += 1.0,
-                    'a' => {
-                        if self.cli.play_speed > 1.0 {
-                            self.cli.play_speed -= 1.0
+                    'c' => {
+                        self.ui.status.color_mode = self.ui.status.color_mode.next();
+                        self.redraw();
+                    }
+                    't' => self.ui.status.threads.next(),
+                    'T' => {
+                        self.ui.status.threads.toggle();
+                        self.redraw();
+                    }
+                    'h' => {
+                        self.ui.status.threads.hide = !self.ui.status.threads.hide;
+                        self.redraw();
+                    }
+                    'v' => self.ui.status.reverse = !self.ui.status.reverse,
+                    'm' => self.ui.fault_vis.bar_mode = self.ui.fault_vis.bar_mode.next(),
+                    'e' => self.ui.event_log.visible = !self.ui.event_log.visible,
+                    'f' => self.ui.event_log.toggle_freeze(self.ui.status.cur_event),
+                    'p' => {
+                        let replay = self.ui.fault_vis.toggle_page_grid();
+                        if replay {
+                            self.redraw();
                         }
                     }
+                    's' => {
+                        let fault_vis = &mut self.ui.fault_vis;
+                        fault_vis.sort = fault_vis.sort.next();
+                        self.ui.status.current = format!("sorting files by {}", fault_vis.sort);
+                    }
+                    'g' => {
+                        let fault_vis = &mut self.ui.fault_vis;
+                        fault_vis.group = fault_vis.group.next();
+                        self.ui.status.current = format!("grouping files by {}", fault_vis.group);
+                    }
+                    'r' => self.cli.play_speed += 1.0,
+                    'a' if self.cli.play_speed > 1.0 => self.cli.play_speed -= 1.0,
                     'R' => self.cli.play_speed *= 2.0,
                     'A' => self.cli.play_speed /= 2.0,
+                    '[' => self.scrub(-1),
+                    ']' => self.scrub(1),
+                    '{' => self.scrub(-10),
+                    '}' => self.scrub(10),
                     '<' => self.goto_event(self.get_first_play_event()),
                     '>' => self.goto_event(self.get_last_play_event()),
                     ',' => {
@@ -107,9 +229,44 @@ impl App {
         Ok(())
     }
 
+    /// Moves through the frozen event log, and jumps playback to the selected event.
+    fn handle_event_log(&mut self, app_event: AppEvent) {
+        let played = self.ui.status.cur_event;
+        let log = &mut self.ui.event_log;
+        match app_event {
+            AppEvent::MoveUp => log.move_selection(1, true, played),
+            AppEvent::MoveDown => log.move_selection(1, false, played),
+            AppEvent::PageUp => log.move_selection(log.page_len(), true, played),
+            AppEvent::PageDown => log.move_selection(log.page_len(), false, played),
+            AppEvent::Enter => {
+                if let Some(event) = log.take_selection() {
+                    self.goto_event(event);
+                }
+            }
+            AppEvent::Esc => log.toggle_freeze(played),
+            _ => {}
+        }
+    }
+
     /// Handles the key events and updates the state of [`App`].
     pub fn handle_key_event(&mut self, key_event: KeyEvent) -> color_eyre::Result<()> {
+        if let Some(prompt) = self.ui.status.prompt.as_mut() {
+            match key_event.code {
+                KeyCode::Char(c) => prompt.push(c),
+                KeyCode::Backspace => {
+                    prompt.pop();
+                }
+                KeyCode::Enter => {
+                    let input = self.ui.status.prompt.take().unwrap_or_default();
+                    self.run_prompt(&input);
+                }
+                KeyCode::Esc => self.ui.status.prompt = None,
+                _ => {}
+            }
+            return Ok(());
+        }
         match key_event.code {
+            KeyCode::Char(':') => self.ui.status.prompt = Some(String::new()),
             KeyCode::Char('q') => self.events.send(AppEvent::Quit),
             KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
                 self.events.send(AppEvent::Quit)
@@ -119,14 +276,31 @@ impl App {
 
             KeyCode::Up => self.events.send(AppEvent::MoveUp),
             KeyCode::Down => self.events.send(AppEvent::MoveDown),
+            KeyCode::PageUp => self.events.send(AppEvent::PageUp),
+            KeyCode::PageDown => self.events.send(AppEvent::PageDown),
             KeyCode::Esc => self.events.send(AppEvent::Esc),
             KeyCode::Enter => self.events.send(AppEvent::Enter),
             KeyCode::Char('b') => self.events.send(AppEvent::Char('b')),
             KeyCode::Char('l') => self.events.send(AppEvent::Char('l')),
+            KeyCode::Char('c') => self.events.send(AppEvent::Char('c')),
+            KeyCode::Char('t') => self.events.send(AppEvent::Char('t')),
+            KeyCode::Char('T') => self.events.send(AppEvent::Char('T')),
+            KeyCode::Char('h') => self.events.send(AppEvent::Char('h')),
+            KeyCode::Char('v') => self.events.send(AppEvent::Char('v')),
+            KeyCode::Char('m') => self.events.send(AppEvent::Char('m')),
+            KeyCode::Char('e') => self.events.send(AppEvent::Char('e')),
+            KeyCode::Char('f') => self.events.send(AppEvent::Char('f')),
+            KeyCode::Char('p') => self.events.send(AppEvent::Char('p')),
+            KeyCode::Char('s') => self.events.send(AppEvent::Char('s')),
+            KeyCode::Char('g') => self.events.send(AppEvent::Char('g')),
             KeyCode::Char('A') => self.events.send(AppEvent::Char('A')),
             KeyCode::Char('a') => self.events.send(AppEvent::Char('a')),
             KeyCode::Char('R') => self.events.send(AppEvent::Char('R')),
             KeyCode::Char('r') => self.events.send(AppEvent::Char('r')),
+            KeyCode::Char('[') => self.events.send(AppEvent::Char('[')),
+            KeyCode::Char(']') => self.events.send(AppEvent::Char(']')),
+            KeyCode::Char('{') => self.events.send(AppEvent::Char('{')),
+            KeyCode::Char('}') => self.events.send(AppEvent::Char('}')),
             KeyCode::Char(',') => self.events.send(AppEvent::Char(',')),
             KeyCode::Char('.') => self.events.send(AppEvent::Char('.')),
             KeyCode::Char(' ') => self.events.send(AppEvent::TogglePause),
@@ -144,11 +318,12 @@ impl App {
         if self.ui.status.paused {
             return;
         }
-        if self.ui.status.looping {
-            if self.ui.status.cur_event >= self.get_last_play_event() {
-                self.ui.reset();
-                self.goto_event(self.get_first_play_event());
-            }
+        if self.ui.status.reverse {
+            self.tick_reverse();
+            return;
+        }
+        if self.ui.status.looping && self.ui.status.cur_event >= self.get_last_play_event() {
+            self.goto_event(self.get_first_play_event());
         }
         match self.cli.play_mode {
             crate::PlaybackMode::FrameTime => {
@@ -160,7 +335,9 @@ impl App {
                         break;
                     }
                     self.increment_counter(count);
-                    if self.ui.status.cur_event >= self.get_last_play_event() {
+                    if self.ui.status.paused
+                        || self.ui.status.cur_event >= self.get_last_play_event()
+                    {
                         break;
                     }
                 }
@@ -170,7 +347,7 @@ impl App {
                 loop {
                     self.increment_counter(1);
                     i += 1;
-                    if i as f32 > self.cli.play_speed {
+                    if self.ui.status.paused || i as f32 > self.cli.play_speed {
                         break;
                     }
                     if self.ui.status.cur_event >= self.get_last_play_event() {
@@ -195,7 +372,9 @@ impl App {
                         break;
                     }
                     self.increment_counter(count);
-                    if self.ui.status.cur_event >= self.get_last_play_event() {
+                    if self.ui.status.paused
+                        || self.ui.status.cur_event >= self.get_last_play_event()
+                    {
                         break;
                     }
                 }
@@ -203,11 +382,203 @@ impl App {
         }
     }
 
+    /// Play backwards by as much as a forward tick would have played.
+    fn tick_reverse(&mut self) {
+        let first = self.get_first_play_event();
+        if self.ui.status.cur_event <= first + 1 {
+            if self.ui.status.looping {
+                self.seek(self.get_last_play_event());
+            }
+            return;
+        }
+        let dt = match self.cli.play_mode {
+            crate::PlaybackMode::FrameStep => {
+                let count = (self.cli.play_speed as usize).max(1);
+                self.seek(
+                    self.ui
+                        .status
+                        .cur_event
+                        .saturating_sub(count)
+                        .max(first + 1),
+                );
+                return;
+            }
+            crate::PlaybackMode::FrameTime => Duration::from_secs_f64(self.cli.play_speed as f64),
+            crate::PlaybackMode::Realtime => {
+                Duration::from_secs_f64(self.cli.play_speed as f64 / TICK_FPS)
+            }
+        };
+        let time = self.ui.status.cur_time.saturating_sub(dt);
+        let faults = &self.data.records.slice()[first..self.ui.status.cur_event];
+        // Keep the events at or before the new time.
+        let event = (first + faults.partition_point(|f| f.time() <= time)).max(first + 1);
+        if event < self.ui.status.cur_event {
+            self.seek(event);
+        }
+        self.ui.status.cur_time = time.max(self.data.records.slice()[event - 1].time());
+    }
+
+    fn run_prompt(&mut self, input: &str) {
+        let input = input.trim();
+        if let Some(spec) = input.strip_prefix("break ") {
+            match Breakpoint::new(spec, &self.data) {
+                Ok(bp) => self.ui.breakpoints.list.push(bp),
+                Err(e) => self.ui.status.current = format!("{}: {}", input, e),
+            }
+            return;
+        }
+        if let Some(spec) = input.strip_prefix("watch ") {
+            match Breakpoint::watch(spec, &self.data) {
+                Ok(bp) => self.ui.breakpoints.list.push(bp),
+                Err(e) => self.ui.status.current = format!("{}: {}", input, e),
+            }
+            return;
+        }
+        if let Some(which) = input.strip_prefix("delete ") {
+            let which = which.trim();
+            if which == "all" {
+                self.ui.breakpoints.list.clear();
+            } else {
+                match which.parse::<usize>() {
+                    Ok(n) if (1..=self.ui.breakpoints.list.len()).contains(&n) => {
+                        self.ui.breakpoints.list.remove(n - 1);
+                    }
+                    _ => self.ui.status.current = format!("{}: no such breakpoint", input),
+                }
+            }
+            return;
+        }
+        match input.parse::<SeekTarget>() {
+            Ok(SeekTarget::Event(event)) => self.goto_event(event),
+            Ok(SeekTarget::Time(time)) => self.seek_time(time),
+            Ok(SeekTarget::Relative(share)) => {
+                let offset = self.ui.status.end_time.mul_f64(share.abs());
+                let time = if share < 0.0 {
+                    self.ui.status.cur_time.saturating_sub(offset)
+                } else {
+                    self.ui.status.cur_time + offset
+                };
+                self.seek_time(time);
+            }
+            Err(e) => self.ui.status.current = format!("{}: {}", input, e),
+        }
+    }
+
+    /// Show every event up to and including `time`.
+    pub fn seek_time(&mut self, time: Duration) {
+        let time = time.min(self.ui.status.end_time);
+        let event = self
+            .data
+            .records
+            .slice()
+            .partition_point(|f| f.time() <= time);
+        self.seek(event);
+        self.ui.status.cur_time = time;
+    }
+
+    /// Move along the timeline by `columns` of its columns.
+    fn scrub(&mut self, columns: i32) {
+        let step = self.ui.timeline.column_time() * columns.unsigned_abs();
+        let time = if columns < 0 {
+            self.ui.status.cur_time.saturating_sub(step)
+        } else {
+            self.ui.status.cur_time + step
+        };
+        self.seek_time(time);
+    }
+
     pub fn goto_event(&mut self, event: usize) {
-        self.ui.status.cur_event = event;
+        self.seek(event);
         self.increment_counter(1);
     }
 
+    /// Rebuild the display as it would look after playing forward through every event before
+    /// `event`, ignoring breakpoints on the way.
+    pub fn seek(&mut self, event: usize) {
+        let event = event.min(self.ui.status.num_events);
+        self.ui.reset();
+        let settings = self.ui.draw_settings();
+        let state_size = self.ui.fault_vis.state_size();
+        let mut done = match self.keyframes.before(event, settings, state_size) {
+            Some((done, state)) => {
+                self.ui.fault_vis.restore(state);
+                done
+            }
+            None => 0,
+        };
+        while done < event {
+            let end = self.keyframes.next(done).min(event);
+            if !self.replay(&mut done, end) {
+                break;
+            }
+        }
+        let faults = &self.data.records.slice()[..event];
+        if let Some(last) = faults.last() {
+            self.ui
+                .status
+                .fault(event - 1, &faults[event - 1..], &self.data, false);
+            self.ui.status.cur_time = last.time();
+        }
+        self.ui.status.cur_event = event;
+    }
+
+    /// Play forward from `done` to `end`, which is no further than the next snapshot, ignoring
+    /// breakpoints, and take the snapshot if it's due. Returns false if playback stopped short.
+    fn replay(&mut self, done: &mut usize, end: usize) -> bool {
+        let faults = &self.data.records.slice()[..end];
+        while *done < end {
+            let res = self.ui.fault_vis.fault(
+                &faults[*done..end],
+                &self.data,
+                &self.ui.map,
+                &self.ui.status,
+            );
+            if res.count == 0 {
+                return false;
+            }
+            *done += res.count;
+        }
+        self.keyframes
+            .record(*done, || self.ui.fault_vis.snapshot());
+        true
+    }
+
+    /// Take a few more snapshots for the current settings between frames, so seeking gets faster
+    /// without holding up startup. The display is put back as it was afterwards.
+    fn index(&mut self) {
+        let num_events = self.ui.status.num_events;
+        let settings = self.ui.draw_settings();
+        let state_size = self.ui.fault_vis.state_size();
+        let latest = self
+            .keyframes
+            .before(num_events, settings.clone(), state_size)
+            .map_or(0, |(done, _)| done);
+        if self.keyframes.next(latest) > num_events {
+            self.ui.status.indexed = None;
+            return;
+        }
+        let shown = self.ui.fault_vis.snapshot();
+        let mut done = match self.keyframes.before(num_events, settings, state_size) {
+            Some((done, state)) => {
+                self.ui.fault_vis.restore(state);
+                done
+            }
+            None => {
+                self.ui.fault_vis.reset();
+                0
+            }
+        };
+        let start = Instant::now();
+        while start.elapsed() < INDEX_BUDGET {
+            let end = self.keyframes.next(done);
+            if end > num_events || !self.replay(&mut done, end) {
+                break;
+            }
+        }
+        self.ui.fault_vis.restore(&shown);
+        self.ui.status.indexed = Some(done as f64 / num_events as f64);
+    }
+
     pub fn get_last_play_event(&self) -> usize {
         if let Some(b) = self.ui.status.marker_b {
             b.min(self.ui.status.num_events)
@@ -253,18 +624,36 @@ impl App {
         let faults =
             &self.data.records.slice()[self.ui.status.cur_event..self.get_last_play_event()];
         let count = count.min(faults.len());
-        let faults = &faults[0..count];
-        if faults.len() == 0 {
+        let mut faults = &faults[0..count];
+        if faults.is_empty() {
             return;
         }
+        let stop = self
+            .ui
+            .breakpoints
+            .first_hit(self.ui.status.cur_event, faults);
+        if let Some(stop) = stop {
+            faults = &faults[..=stop];
+        }
 
-        let res = self.ui.fault_vis.fault(faults, &self.data, &self.ui.map);
+        let mut res = self
+            .ui
+            .fault_vis
+            .fault(faults, &self.data, &self.ui.map, &self.ui.status);
         if res.count == 0 {
             return;
         }
+        if let Some(stop) = stop
+            && res.count == faults.len()
+        {
+            self.ui
+                .breakpoints
+                .hit(self.ui.status.cur_event + stop, &faults[stop]);
+            res.hit_breakpoint = true;
+        }
         self.ui.status.fault(
             self.ui.status.cur_event,
-            faults,
+            &faults[..res.count],
             &self.data,
             res.hit_breakpoint,
         );
@@ -280,8 +669,64 @@ impl App {
         }
     }
 
-    pub fn decrement_counter(&mut self) {}
+    /// Replay up to the current event, so that a change to how events are drawn also applies to
+    /// the ones already played.
+    fn redraw(&mut self) {
+        let time = self.ui.status.cur_time;
+        self.seek(self.ui.status.cur_event);
+        self.ui.status.cur_time = time;
+    }
+
+    /// Step back over the last event played.
+    pub fn decrement_counter(&mut self) {
+        self.seek(self.ui.status.cur_event.saturating_sub(1));
+    }
+
     pub fn set_pause(&mut self, pause: bool) {
         self.ui.status.paused = pause;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek_targets() {
+        assert_eq!(
+            "event 123".parse::<SeekTarget>().unwrap(),
+            SeekTarget::Event(123)
+        );
+        assert_eq!(
+            " time 1:02.5 ".parse::<SeekTarget>().unwrap(),
+            SeekTarget::Time(Duration::from_millis(62_500))
+        );
+        assert_eq!(
+            "+10%".parse::<SeekTarget>().unwrap(),
+            SeekTarget::Relative(0.1)
+        );
+        assert_eq!(
+            "-5%".parse::<SeekTarget>().unwrap(),
+            SeekTarget::Relative(-0.05)
+        );
+        assert!("10%".parse::<SeekTarget>().is_err());
+        assert!("event x".parse::<SeekTarget>().is_err());
+        assert!("goto 5".parse::<SeekTarget>().is_err());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("5").unwrap(), Duration::from_secs(5));
+        assert_eq!(
+            parse_time("01:23.500").unwrap(),
+            Duration::from_millis(83_500)
+        );
+        assert_eq!(
+            parse_time("2:00:00.000000001").unwrap(),
+            Duration::new(7200, 1)
+        );
+        assert!(parse_time("1:2:3:4").is_err());
+        assert!(parse_time("1.0000000001").is_err());
+        assert!(parse_time("1.-5").is_err());
+    }
+}
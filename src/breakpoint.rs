@@ -0,0 +1,365 @@
+//! Breakpoints with conditions, set from the `:` prompt with `break <condition>...`:
+//!
+//! - `file NAME`: the event is in a file whose path contains NAME
+//! - `kind major|minor|miss`
+//! - `offset START..END`: the offset into the file is in the range (sizes like `64MiB` work)
+//! - `page OFFSET`: the offset into the file is in the same page as OFFSET
+//! - `tid TID`
+//! - `ip START..END` or `ip ADDR`
+//! - `func NAME`: the instruction that caused the event is in a function whose name contains NAME
+//! - `source FILE[:LINE]`: the instruction is in a source file whose path contains FILE, and on
+//!   LINE if given
+//! - `nth N`: only stop at the Nth event matching the other conditions
+//!
+//! An event has to match every condition for the breakpoint to stop playback.
+//!
+//! Watchpoints are set with `watch FILE OFFSET [touch N]`, and stop playback when the page holding
+//! OFFSET faults again after being evicted, i.e. takes a major fault after an earlier fault. With
+//! `touch N`, they also stop at the Nth fault to the page.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    ops::Range,
+};
+
+use color_eyre::eyre::{OptionExt, Result, bail};
+
+use crate::{
+    perf::{EventKind, EventRecord, FaultData, PAGE_SIZE},
+    symbols::CodeLocation,
+};
+
+#[derive(Debug, Default)]
+struct Condition {
+    objects: Option<HashSet<usize>>,
+    kind: Option<EventKind>,
+    offset: Option<Range<u64>>,
+    tid: Option<u32>,
+    ip: Option<Range<u64>>,
+    /// Code locations (indices into the trace's code table) in the functions or source lines
+    /// asked for.
+    code: Option<HashSet<usize>>,
+}
+
+impl Condition {
+    fn matches(&self, fault: &EventRecord) -> bool {
+        self.objects
+            .as_ref()
+            .is_none_or(|objects| objects.contains(&fault.obj_id()))
+            && self.kind.is_none_or(|kind| kind == fault.kind())
+            && self
+                .offset
+                .as_ref()
+                .is_none_or(|range| range.contains(&fault.offset()))
+            && self.tid.is_none_or(|tid| tid == fault.tid())
+            && self
+                .ip
+                .as_ref()
+                .is_none_or(|range| range.contains(&fault.ip()))
+            && self
+                .code
+                .as_ref()
+                .is_none_or(|code| fault.code().is_some_and(|idx| code.contains(&idx)))
+    }
+
+    /// Narrow down the code matched to the locations that pass `filter`.
+    fn restrict_code(
+        &mut self,
+        data: &FaultData,
+        what: &str,
+        filter: impl Fn(&CodeLocation) -> bool,
+    ) -> Result<()> {
+        let code = data
+            .json
+            .code
+            .iter()
+            .enumerate()
+            .filter(|(idx, location)| {
+                filter(location) && self.code.as_ref().is_none_or(|code| code.contains(idx))
+            })
+            .map(|(idx, _)| idx)
+            .collect::<HashSet<_>>();
+        if code.is_empty() {
+            bail!("no code in the trace matches {}", what);
+        }
+        self.code = Some(code);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Trigger {
+    Condition {
+        cond: Condition,
+        /// With `nth`, the index of the only event to stop at.
+        nth_event: Option<usize>,
+    },
+    /// Stop at exactly these events, sorted.
+    Events(Vec<usize>),
+}
+
+#[derive(Debug)]
+pub struct Breakpoint {
+    /// The conditions as they were typed.
+    spec: String,
+    trigger: Trigger,
+    /// How many times the breakpoint has stopped playback.
+    pub hits: usize,
+}
+
+impl Breakpoint {
+    pub fn new(spec: &str, data: &FaultData) -> Result<Self> {
+        let mut cond = Condition::default();
+        let mut nth = None;
+        let mut words = spec.split_whitespace();
+        while let Some(word) = words.next() {
+            let arg = words
+                .next()
+                .ok_or_else(|| color_eyre::eyre::eyre!("`{}' needs a value", word))?;
+            match word {
+                "file" => cond.objects = Some(file_objects(arg, data)?),
+                "kind" => {
+                    cond.kind = Some(match EventKind::from(arg) {
+                        EventKind::Unknown => bail!("unknown event kind `{}'", arg),
+                        kind => kind,
+                    })
+                }
+                "offset" => cond.offset = Some(parse_range(arg)?),
+                "page" => cond.offset = Some(page_range(arg)?),
+                "tid" => cond.tid = Some(arg.parse()?),
+                "ip" => {
+                    cond.ip = Some(match arg.contains("..") {
+                        true => parse_range(arg)?,
+                        false => {
+                            let ip = parse_size(arg)?;
+                            let end = ip
+                                .checked_add(1)
+                                .ok_or_eyre(format!("ip `{}' is out of range", arg))?;
+                            ip..end
+                        }
+                    })
+                }
+                "func" => {
+                    let name = |loc: &CodeLocation| {
+                        loc.function
+                            .and_then(|f| data.json.strings.resolve(f))
+                            .is_some_and(|f| f.contains(arg))
+                    };
+                    cond.restrict_code(data, &format!("function `{}'", arg), name)?
+                }
+                "source" => {
+                    let (file, line) = match arg.rsplit_once(':') {
+                        Some((file, line)) => (file, Some(line.parse::<u32>()?)),
+                        None => (arg, None),
+                    };
+                    let source = |loc: &CodeLocation| {
+                        loc.source_file
+                            .and_then(|f| data.json.strings.resolve(f))
+                            .is_some_and(|f| f.contains(file))
+                            && line.is_none_or(|line| loc.line == Some(line))
+                    };
+                    cond.restrict_code(data, &format!("source `{}'", arg), source)?
+                }
+                "nth" => nth = Some(arg.parse::<usize>()?),
+                _ => bail!("unknown breakpoint condition `{}'", word),
+            }
+        }
+
+        let nth_event = match nth {
+            None => None,
+            Some(0) => bail!("`nth' counts from 1"),
+            Some(n) => {
+                let matching = data
+                    .records
+                    .slice()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, fault)| cond.matches(fault));
+                let (idx, _) = matching
+                    .clone()
+                    .nth(n - 1)
+                    .ok_or_eyre(format!("only {} events match", matching.count()))?;
+                Some(idx)
+            }
+        };
+
+        Ok(Self {
+            spec: spec.split_whitespace().collect::<Vec<_>>().join(" "),
+            trigger: Trigger::Condition { cond, nth_event },
+            hits: 0,
+        })
+    }
+
+    /// A watchpoint on a page, from `FILE OFFSET [touch N]`.
+    pub fn watch(spec: &str, data: &FaultData) -> Result<Self> {
+        let words = spec.split_whitespace().collect::<Vec<_>>();
+        let (file, offset, touch) = match words[..] {
+            [file, offset] => (file, offset, None),
+            [file, offset, "touch", n] => (file, offset, Some(n.parse::<usize>()?)),
+            _ => bail!("expected `watch FILE OFFSET [touch N]'"),
+        };
+        if touch == Some(0) {
+            bail!("`touch' counts from 1");
+        }
+        let cond = Condition {
+            objects: Some(file_objects(file, data)?),
+            offset: Some(page_range(offset)?),
+            ..Default::default()
+        };
+
+        // Walk the page's faults once up front, so that stopping doesn't depend on what has been
+        // played, which seeking would throw off. Pages are told apart per file.
+        let mut touches = HashMap::new();
+        let mut events = Vec::new();
+        for (idx, fault) in data.records.slice().iter().enumerate() {
+            if !fault.kind().is_fault() || !cond.matches(fault) {
+                continue;
+            }
+            let count = touches.entry(fault.obj_id()).or_insert(0);
+            *count += 1;
+            if (fault.kind() == EventKind::MajorFault && *count > 1) || touch == Some(*count) {
+                events.push(idx);
+            }
+        }
+
+        Ok(Self {
+            spec: format!("watch {}", words.join(" ")),
+            trigger: Trigger::Events(events),
+            hits: 0,
+        })
+    }
+
+    /// Whether playback should stop at event `idx`.
+    fn stops_at(&self, idx: usize, fault: &EventRecord) -> bool {
+        match &self.trigger {
+            Trigger::Condition { cond, nth_event } => {
+                nth_event.is_none_or(|nth| nth == idx) && cond.matches(fault)
+            }
+            Trigger::Events(events) => events.binary_search(&idx).is_ok(),
+        }
+    }
+}
+
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} hits)", self.spec, self.hits)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    pub list: Vec<Breakpoint>,
+}
+
+impl Breakpoints {
+    /// Find the first of `faults`, which start at event `first`, that a breakpoint stops at.
+    pub fn first_hit(&self, first: usize, faults: &[EventRecord]) -> Option<usize> {
+        if self.list.is_empty() {
+            return None;
+        }
+        faults
+            .iter()
+            .enumerate()
+            .position(|(i, fault)| self.list.iter().any(|bp| bp.stops_at(first + i, fault)))
+    }
+
+    /// Count a stop at event `idx` against each breakpoint that caused it.
+    pub fn hit(&mut self, idx: usize, fault: &EventRecord) {
+        for bp in &mut self.list {
+            if bp.stops_at(idx, fault) {
+                bp.hits += 1;
+            }
+        }
+    }
+}
+
+/// Objects whose path contains `name`.
+fn file_objects(name: &str, data: &FaultData) -> Result<HashSet<usize>> {
+    let objects = data
+        .json
+        .objects
+        .values()
+        .filter(|obj| {
+            data.json
+                .strings
+                .resolve(obj.file)
+                .is_some_and(|file| file.contains(name))
+        })
+        .map(|obj| obj.idx)
+        .collect::<HashSet<_>>();
+    if objects.is_empty() {
+        bail!("no file matches `{}'", name);
+    }
+    Ok(objects)
+}
+
+/// Parse a size or address: decimal, `0x` hex, or with a K/M/G(iB) suffix.
+fn parse_size(s: &str) -> Result<u64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        return Ok(u64::from_str_radix(hex, 16)?);
+    }
+    let s = s.trim_end_matches("iB").trim_end_matches('B');
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    num.parse::<u64>()?
+        .checked_mul(1 << shift)
+        .ok_or_eyre(format!("size `{}' is out of range", s))
+}
+
+/// Parse an offset into the range of the page it's in.
+fn page_range(s: &str) -> Result<Range<u64>> {
+    let page = parse_size(s)? / PAGE_SIZE * PAGE_SIZE;
+    let end = page
+        .checked_add(PAGE_SIZE)
+        .ok_or_eyre(format!("page `{}' is out of range", s))?;
+    Ok(page..end)
+}
+
+/// Parse `START..END`.
+fn parse_range(s: &str) -> Result<Range<u64>> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_eyre(format!("expected a range START..END, got `{}'", s))?;
+    let range = parse_size(start)?..parse_size(end)?;
+    if range.is_empty() {
+        bail!("range `{}' is empty", s);
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("0x1000").unwrap(), 0x1000);
+        assert_eq!(parse_size("4K").unwrap(), 4 << 10);
+        assert_eq!(parse_size("64MiB").unwrap(), 64 << 20);
+        assert_eq!(parse_size("2GB").unwrap(), 2 << 30);
+        assert!(parse_size("12Q").is_err());
+        assert!(parse_size("0xzz").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
+
+    #[test]
+    fn pages() {
+        assert_eq!(page_range("5000").unwrap(), 4096..8192);
+        assert!(page_range("0xffffffffffffffff").is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("0..4K").unwrap(), 0..4096);
+        assert_eq!(parse_range("0x1000..0x2000").unwrap(), 0x1000..0x2000);
+        assert!(parse_range("4K").is_err());
+        assert!(parse_range("8K..4K").is_err());
+        assert!(parse_range("4K..4K").is_err());
+    }
+}
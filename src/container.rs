@@ -0,0 +1,502 @@
+//! The single-file trace format. A header and section table are followed by the sections: the
+//! records file (see [`perf::write_records`]), the objects and the string table as JSON, metadata
+//! about the capture, and where in the code the events came from. Each section has a checksum,
+//! and the table itself is checksummed, so a trace whose parts don't belong together is rejected
+//! instead of being misread.
+//!
+//! The records come last, so that a trace cut short only loses events, and are aligned so that
+//! they can be used straight out of an mmap.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::{OptionExt, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::perf::{self, FaultData, Interner, JsonRoot, Object, PerfData, Records};
+
+pub const MAGIC: &[u8; 8] = b"PFVIZTRC";
+pub const VERSION: u32 = 1;
+pub const EXTENSION: &str = "pfv";
+
+const SECTION_RECORDS: u32 = 1;
+const SECTION_OBJECTS: u32 = 2;
+const SECTION_STRINGS: u32 = 3;
+const SECTION_META: u32 = 4;
+/// Where the events' instruction pointers are in the code. Older traces don't have it.
+const SECTION_CODE: u32 = 5;
+
+const SECTION_ALIGN: u64 = 64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    nr_sections: u32,
+    /// CRC32 of the section table.
+    table_crc: u32,
+    _pad: u32,
+    _resv: [u64; 5],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct SectionEntry {
+    kind: u32,
+    /// CRC32 of the section's contents.
+    crc: u32,
+    offset: u64,
+    len: u64,
+}
+
+/// Where a trace came from.
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CaptureMeta {
+    pub pfviz_version: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Capture backend ("native" or "perf"), or "import".
+    pub source: String,
+    pub command: Vec<String>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub events: Vec<String>,
+    /// The file a trace was imported from.
+    pub input: Option<PathBuf>,
+}
+
+impl CaptureMeta {
+    pub fn new(source: &str) -> Self {
+        Self {
+            pfviz_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            source: source.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Path of a single-file trace: `path` itself if it has the trace extension, or `path` with the
+/// extension appended.
+pub fn trace_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == EXTENSION) {
+        return path.to_path_buf();
+    }
+    let mut name = path.to_path_buf().into_os_string();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Check whether a file starts with the single-file trace magic.
+pub fn is_container<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic))
+        .is_ok_and(|_| &magic == MAGIC)
+}
+
+/// Counts and checksums everything written through it.
+struct SectionWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<W: Write> Write for SectionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a trace. The header and section table go first, so that a trace cut short still
+/// identifies itself, and the records go last, so that only they are lost.
+pub fn write_trace<W: Write>(pd: &PerfData, meta: &CaptureMeta, out: W) -> Result<()> {
+    let objects = pd
+        .objects
+        .iter()
+        .map(|x| (x.0, *x.1))
+        .collect::<HashMap<_, _>>();
+    let sections = [
+        (SECTION_OBJECTS, serde_json::to_vec(&objects)?),
+        (SECTION_STRINGS, serde_json::to_vec(&pd.strings)?),
+        (SECTION_META, serde_json::to_vec(meta)?),
+        (SECTION_CODE, serde_json::to_vec(&pd.code)?),
+    ];
+    // The records are too big to hold twice, so they're checksummed in a pass of their own.
+    let mut records = SectionWriter {
+        inner: std::io::sink(),
+        hasher: crc32fast::Hasher::new(),
+        len: 0,
+    };
+    perf::write_records(pd, &mut records)?;
+
+    let nr_sections = sections.len() + 1;
+    let mut pos = (size_of::<Header>() + nr_sections * size_of::<SectionEntry>()) as u64;
+    let mut table = Vec::with_capacity(nr_sections);
+    let mut place = |kind: u32, crc: u32, len: u64| {
+        pos = pos.next_multiple_of(SECTION_ALIGN);
+        table.push(SectionEntry {
+            kind,
+            crc,
+            offset: pos,
+            len,
+        });
+        pos += len;
+    };
+    for (kind, bytes) in &sections {
+        place(*kind, crc32fast::hash(bytes), bytes.len() as u64);
+    }
+    place(SECTION_RECORDS, records.hasher.finalize(), records.len);
+
+    let table_bytes: &[u8] = bytemuck::cast_slice(&table);
+    let header = Header {
+        magic: *MAGIC,
+        version: VERSION,
+        nr_sections: table.len() as u32,
+        table_crc: crc32fast::hash(table_bytes),
+        _pad: 0,
+        _resv: [0; 5],
+    };
+    let mut out = BufWriter::new(out);
+    out.write_all(bytemuck::bytes_of(&header))?;
+    out.write_all(table_bytes)?;
+    let mut written = (size_of::<Header>() + table_bytes.len()) as u64;
+    let contents = sections.iter().map(|(_, bytes)| Some(bytes)).chain([None]);
+    for (entry, bytes) in table.iter().zip(contents) {
+        out.write_all(&vec![0; (entry.offset - written) as usize])?;
+        match bytes {
+            Some(bytes) => out.write_all(bytes)?,
+            None => perf::write_records(pd, &mut out)?,
+        }
+        written = entry.offset + entry.len;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub fn read_trace<P: AsRef<Path>>(path: P) -> Result<FaultData> {
+    let file = File::open(path)?;
+    let map = unsafe { memmap2::Mmap::map(&file) }?;
+
+    let header: Header = map
+        .get(..size_of::<Header>())
+        .map(bytemuck::pod_read_unaligned)
+        .ok_or_eyre("truncated trace header")?;
+    if &header.magic != MAGIC {
+        bail!("not a pfviz trace");
+    }
+    if header.version > VERSION {
+        bail!(
+            "trace is format version {}, but this pfviz only supports up to version {}",
+            header.version,
+            VERSION
+        );
+    }
+
+    let table_bytes = map
+        .get(
+            size_of::<Header>()
+                ..size_of::<Header>() + header.nr_sections as usize * size_of::<SectionEntry>(),
+        )
+        .ok_or_eyre("truncated trace section table")?;
+    if crc32fast::hash(table_bytes) != header.table_crc {
+        bail!("trace section table is corrupt (checksum mismatch)");
+    }
+    let table = table_bytes
+        .chunks_exact(size_of::<SectionEntry>())
+        .map(bytemuck::pod_read_unaligned::<SectionEntry>)
+        .collect::<Vec<_>>();
+
+    let section = |kind: u32, name: &str| -> Result<Option<(usize, &[u8])>> {
+        let Some(entry) = table.iter().find(|e| e.kind == kind) else {
+            return Ok(None);
+        };
+        let range = entry.offset as usize..entry.offset.saturating_add(entry.len) as usize;
+        let Some(bytes) = map.get(range) else {
+            bail!("trace {} section extends past end of file", name);
+        };
+        if crc32fast::hash(bytes) != entry.crc {
+            bail!("trace {} section is corrupt (checksum mismatch)", name);
+        }
+        Ok(Some((entry.offset as usize, bytes)))
+    };
+
+    // A trace cut short loses the end of its records, which come last. The whole records that are
+    // left can still be played.
+    let records = table
+        .iter()
+        .find(|e| e.kind == SECTION_RECORDS)
+        .ok_or_eyre("trace has no records section")?;
+    let records_start = records.offset as usize;
+    let records_len = if records.offset.saturating_add(records.len) > map.len() as u64 {
+        if records_start > map.len() {
+            bail!("trace is truncated before its records");
+        }
+        tracing::warn!(
+            "trace is truncated: {} of {} bytes of records are present, not checking them",
+            map.len() - records_start,
+            records.len
+        );
+        map.len() - records_start
+    } else {
+        section(SECTION_RECORDS, "records")?;
+        records.len as usize
+    };
+    let (_, objects) =
+        section(SECTION_OBJECTS, "objects")?.ok_or_eyre("trace has no objects section")?;
+    let (_, strings) =
+        section(SECTION_STRINGS, "strings")?.ok_or_eyre("trace has no strings section")?;
+    let objects: HashMap<usize, Object> = serde_json::from_slice(objects)?;
+    let strings: Interner = serde_json::from_slice(strings)?;
+    let meta = match section(SECTION_META, "metadata")? {
+        Some((_, meta)) => serde_json::from_slice(meta)?,
+        None => CaptureMeta::default(),
+    };
+    let code = match section(SECTION_CODE, "code")? {
+        Some((_, code)) => serde_json::from_slice(code)?,
+        None => Vec::new(),
+    };
+
+    Ok(FaultData {
+        json: JsonRoot {
+            objects,
+            strings,
+            code,
+        },
+        records: Records::new(map, records_start, records_len)?,
+        meta,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Range, time::Duration};
+
+    use stable_vec::StableVec;
+
+    use super::*;
+    use crate::{
+        perf::{Event, EventKind, SpilledRecords},
+        symbols::CodeLocation,
+    };
+
+    const EVENTS: usize = 100;
+
+    fn perf_data() -> PerfData {
+        let mut strings = Interner::default();
+        let file = strings.get_or_intern("/data/x");
+        let name = strings.get_or_intern("minor-faults");
+        let function = strings.get_or_intern("main");
+        let mut objects = StableVec::new();
+        objects.push(Object {
+            file,
+            idx: 0,
+            maps: 1,
+            faults: EVENTS,
+            biggest_offset: 0x10000,
+            smallest_offset: 0,
+            show: true,
+            size: 0x10000,
+        });
+        let faults = (0..EVENTS as u64)
+            .map(|i| Event {
+                obj_idx: 0,
+                offset: i * 0x1000,
+                was_write: Some(i % 2 == 0),
+                time: Duration::from_millis(i),
+                kind: EventKind::MinorFault,
+                event_name: name,
+                addr: 0x7f00_0000_0000 + i * 0x1000,
+                ip: 0x401000,
+                code: (i % 2 == 0).then_some(0),
+                tid: 1234,
+                cpu: (i % 4) as u32,
+            })
+            .collect();
+        PerfData {
+            faults,
+            objects,
+            spilled: None,
+            strings,
+            code: vec![CodeLocation {
+                binary: None,
+                offset: 0x401000,
+                function: Some(function),
+                source_file: None,
+                line: None,
+            }],
+        }
+    }
+
+    /// Write a test trace, letting `mangle` change its bytes, and read it back.
+    fn round_trip(name: &str, mangle: impl FnOnce(&mut Vec<u8>)) -> Result<FaultData> {
+        round_trip_data(name, &perf_data(), mangle)
+    }
+
+    fn round_trip_data(
+        name: &str,
+        pd: &PerfData,
+        mangle: impl FnOnce(&mut Vec<u8>),
+    ) -> Result<FaultData> {
+        let mut bytes = Vec::new();
+        write_trace(pd, &CaptureMeta::new("test"), &mut bytes)?;
+        mangle(&mut bytes);
+        let path = test_path(name);
+        std::fs::write(&path, &bytes)?;
+        let data = read_trace(&path);
+        std::fs::remove_file(&path)?;
+        data
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "pfviz-test-{}-{}.{}",
+            std::process::id(),
+            name,
+            EXTENSION
+        ))
+    }
+
+    /// Where the section of `kind` is in a written trace.
+    fn section_range(bytes: &[u8], kind: u32) -> Range<usize> {
+        let header: Header = bytemuck::pod_read_unaligned(&bytes[..size_of::<Header>()]);
+        (0..header.nr_sections as usize)
+            .map(|i| {
+                let start = size_of::<Header>() + i * size_of::<SectionEntry>();
+                bytemuck::pod_read_unaligned::<SectionEntry>(
+                    &bytes[start..start + size_of::<SectionEntry>()],
+                )
+            })
+            .find(|entry| entry.kind == kind)
+            .map(|entry| entry.offset as usize..(entry.offset + entry.len) as usize)
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let data = round_trip("round-trip", |_| {}).unwrap();
+        let pd = perf_data();
+        assert_eq!(data.records.slice().len(), EVENTS);
+        for (record, event) in data.records.slice().iter().zip(&pd.faults) {
+            assert_eq!(record.offset(), event.offset);
+            assert_eq!(record.time(), event.time);
+            assert_eq!(record.kind(), event.kind);
+            assert_eq!(record.was_write(), event.was_write);
+            assert_eq!(record.addr(), event.addr);
+            assert_eq!(record.cpu(), event.cpu);
+            assert_eq!(record.code(), event.code.map(|code| code as usize));
+        }
+        assert_eq!(data.object_name(&data.records.slice()[0]), "/data/x");
+        let [first, second] = &data.records.slice()[..2] else {
+            unreachable!()
+        };
+        assert_eq!(data.code_location(first).to_string(), "main");
+        assert_eq!(data.code_location(second).to_string(), "0x401000");
+        assert_eq!(data.meta.source, "test");
+    }
+
+    #[test]
+    fn rejects_corrupt_sections() {
+        for (kind, name) in [
+            (SECTION_OBJECTS, "objects"),
+            (SECTION_STRINGS, "strings"),
+            (SECTION_RECORDS, "records"),
+        ] {
+            let err = round_trip(&format!("corrupt-{}", name), |bytes| {
+                let range = section_range(bytes, kind);
+                bytes[range.start + range.len() / 2] ^= 0xff;
+            })
+            .unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains(&format!("{} section is corrupt", name)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_table() {
+        let err =
+            round_trip("corrupt-table", |bytes| bytes[size_of::<Header>()] ^= 0xff).unwrap_err();
+        assert!(
+            err.to_string().contains("section table is corrupt"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn plays_whole_records_of_truncated_trace() {
+        // Cut the last record in half.
+        let record = size_of::<perf::EventRecord>();
+        let data = round_trip("truncated", |bytes| {
+            bytes.truncate(bytes.len() - record / 2)
+        })
+        .unwrap();
+        assert_eq!(data.records.slice().len(), EVENTS - 1);
+        assert_eq!(data.object_name(&data.records.slice()[0]), "/data/x");
+        let [first, second] = &data.records.slice()[..2] else {
+            unreachable!()
+        };
+        assert_eq!(data.code_location(first).to_string(), "main");
+        assert_eq!(data.code_location(second).to_string(), "0x401000");
+    }
+
+    #[test]
+    fn rejects_trace_cut_before_records() {
+        let err = round_trip("truncated-header", |bytes| {
+            let records = section_range(bytes, SECTION_RECORDS);
+            bytes.truncate(records.start);
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("too short"), "{}", err);
+    }
+
+    #[test]
+    fn reads_back_spilled_records() {
+        let mut pd = perf_data();
+        let path = test_path("spill");
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut spilled = SpilledRecords::new(file);
+        // Some records spilled and some not, which isn't how any backend uses it, but should
+        // still come out in order.
+        for event in pd.faults.drain(..EVENTS / 2) {
+            spilled.push(&event).unwrap();
+        }
+        spilled.flush().unwrap();
+        pd.spilled = Some(spilled);
+
+        let data = round_trip_data("spilled", &pd, |_| {}).unwrap();
+        assert_eq!(data.records.slice().len(), EVENTS);
+        for (record, event) in data.records.slice().iter().zip(&perf_data().faults) {
+            assert_eq!(record.offset(), event.offset);
+            assert_eq!(record.time(), event.time);
+        }
+    }
+}
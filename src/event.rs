@@ -42,6 +42,8 @@ pub enum AppEvent {
     TogglePause,
     MoveUp,
     MoveDown,
+    PageUp,
+    PageDown,
     Enter,
     Esc,
     Char(char),
@@ -65,6 +67,13 @@ impl EventHandler {
         Self { sender, receiver }
     }
 
+    /// Constructs an [`EventHandler`] without the event thread, for driving the application
+    /// without a terminal.
+    pub fn headless() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self { sender, receiver }
+    }
+
     /// Receives an event from the sender.
     ///
     /// This function blocks until an event is received.
@@ -89,6 +98,12 @@ impl EventHandler {
     }
 }
 
+impl Default for EventHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// A thread that handles reading crossterm events and emitting tick events on a regular schedule.
 struct EventThread {
     /// Event sender channel.
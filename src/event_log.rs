@@ -0,0 +1,148 @@
+//! A pane listing the events played most recently, with every field of their records. It can be
+//! frozen to look back through them, and to jump playback to one.
+
+use std::cell::Cell;
+
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Rect},
+    style::{Style, Stylize},
+    widgets::{Block, Borders, Row, Table, Widget},
+};
+
+use crate::perf::FaultData;
+
+#[derive(Debug)]
+pub struct EventLog {
+    pub visible: bool,
+    /// While frozen, the event the log ends before and the selected event. Otherwise the log
+    /// follows playback.
+    frozen: Option<(usize, usize)>,
+    /// Events that fit in the pane, as of the last draw.
+    rows: Cell<usize>,
+}
+
+impl EventLog {
+    pub fn new(visible: bool) -> Self {
+        Self {
+            visible,
+            frozen: None,
+            rows: Cell::new(1),
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.is_some()
+    }
+
+    /// Events shown at once, for paging through them.
+    pub fn page_len(&self) -> usize {
+        self.rows.get()
+    }
+
+    /// Stop following playback, selecting the latest of the `played` events. Unfreezes if already
+    /// frozen.
+    pub fn toggle_freeze(&mut self, played: usize) {
+        self.frozen = match self.frozen {
+            None if played > 0 => Some((played, played - 1)),
+            _ => None,
+        };
+        self.visible |= self.frozen.is_some();
+    }
+
+    /// Move the selection by `step` events, scrolling to keep it in view. There are `played`
+    /// events to choose from.
+    pub fn move_selection(&mut self, step: usize, up: bool, played: usize) {
+        let Some((end, selected)) = self.frozen.as_mut() else {
+            return;
+        };
+        *selected = if up {
+            selected.saturating_sub(step)
+        } else {
+            (*selected + step).min(played.saturating_sub(1))
+        };
+        let rows = self.rows.get().max(1);
+        if *selected < end.saturating_sub(rows) {
+            *end = *selected + rows;
+        } else if *selected >= *end {
+            *end = *selected + 1;
+        }
+    }
+
+    /// The selected event, unfreezing the log to follow playback from there.
+    pub fn take_selection(&mut self) -> Option<usize> {
+        self.frozen.take().map(|(_, selected)| selected)
+    }
+
+    /// Draw the events before `played`, or the frozen ones.
+    pub fn render(&self, area: Rect, buf: &mut Buffer, data: &FaultData, played: usize) {
+        let (end, selected) = self.frozen.unwrap_or((played, usize::MAX));
+        let title = if self.frozen.is_some() {
+            "Events (frozen: (Up/Down) Select; (Enter) Go To Event; (f/Esc) Follow Playback)"
+        } else {
+            "Events ((f) Freeze)"
+        };
+        let block = Block::default().borders(Borders::ALL).title(title);
+        // One row goes to the header.
+        let rows = usize::from(block.inner(area).height.saturating_sub(1));
+        self.rows.set(rows);
+
+        let faults = data.records.slice();
+        let end = end.min(faults.len());
+        let start = end.saturating_sub(rows);
+        let table_rows = faults[start..end].iter().enumerate().map(|(i, fault)| {
+            let idx = start + i;
+            let row = Row::new([
+                idx.to_string(),
+                format!("{:.9}", fault.time().as_secs_f64()),
+                fault.kind().to_string(),
+                data.json
+                    .strings
+                    .resolve(fault.event_name())
+                    .unwrap_or("?")
+                    .to_string(),
+                data.object_name(fault).to_string(),
+                format!("{:#x}", fault.offset()),
+                format!("{:#x}", fault.addr()),
+                format!("{:#x}", fault.ip()),
+                data.code_location(fault).to_string(),
+                fault.tid().to_string(),
+                fault.cpu().to_string(),
+            ]);
+            if idx == selected { row.reversed() } else { row }
+        });
+        let header = Row::new([
+            "event",
+            "time",
+            "kind",
+            "perf event",
+            "object",
+            "offset",
+            "address",
+            "ip",
+            "code",
+            "tid",
+            "cpu",
+        ])
+        .style(Style::default().bold());
+        Table::new(
+            table_rows,
+            [
+                Constraint::Length(10),
+                Constraint::Length(16),
+                Constraint::Length(11),
+                Constraint::Length(16),
+                Constraint::Fill(1),
+                Constraint::Length(12),
+                Constraint::Length(16),
+                Constraint::Length(16),
+                Constraint::Fill(1),
+                Constraint::Length(8),
+                Constraint::Length(4),
+            ],
+        )
+        .header(header)
+        .block(block)
+        .render(area, buf);
+    }
+}
@@ -0,0 +1,150 @@
+//! Snapshots of the playback display, taken every so many events. Seeking restores the nearest
+//! snapshot before the target and only replays the events after it.
+
+use std::{collections::BTreeSet, ops::Range};
+
+use crate::{ColorMode, ui::FaultVisState};
+
+/// How much memory the snapshots of a trace may take, roughly.
+const MEMORY_BUDGET: usize = 256 << 20;
+/// Snapshots are never taken more often than this, since replaying this many events is quick.
+const MIN_INTERVAL: usize = 1 << 12;
+
+/// Settings that change what playing an event draws. Snapshots taken with different settings
+/// can't be reused, so a set of them is kept for each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DrawSettings {
+    pub color_mode: ColorMode,
+    pub picked_threads: BTreeSet<u32>,
+    pub hide_threads: bool,
+    /// The file and byte range shown in the single-file view.
+    pub single_file: Option<(usize, Range<u64>)>,
+    /// The file, byte range and cell size shown in the page grid.
+    pub page_grid: Option<(usize, Range<u64>, u64)>,
+}
+
+/// How many sets of snapshots are kept, so that switching back and forth between views doesn't
+/// replay the trace from the start each time. They share the memory budget.
+const MAX_SETS: usize = 4;
+
+/// Events between snapshots of `state_size` bytes, to keep a set of them within its share of the
+/// memory budget.
+fn interval(num_events: usize, state_size: usize) -> usize {
+    let max_frames = (MEMORY_BUDGET / MAX_SETS / state_size.max(1)).max(1);
+    num_events.div_ceil(max_frames).max(MIN_INTERVAL)
+}
+
+/// Snapshots taken with one set of settings.
+#[derive(Debug)]
+struct Frames {
+    settings: DrawSettings,
+    /// Events between snapshots.
+    interval: usize,
+    /// `frames[i]` is the state after playing the first `(i + 1) * interval` events.
+    frames: Vec<FaultVisState>,
+}
+
+#[derive(Debug)]
+pub struct Keyframes {
+    num_events: usize,
+    /// The sets of snapshots, most recently used first. Never empty once a snapshot is asked for.
+    sets: Vec<Frames>,
+}
+
+impl Keyframes {
+    /// Space snapshots out over `num_events` events.
+    pub fn new(num_events: usize) -> Self {
+        Self {
+            num_events,
+            sets: Vec::new(),
+        }
+    }
+
+    /// Find the latest snapshot at or before `event` taken with `settings`, starting a new set
+    /// of snapshots of `state_size` bytes if there's none for them, and dropping the least
+    /// recently used set if there are too many. Returns the snapshot and the number of events it
+    /// covers.
+    pub fn before(
+        &mut self,
+        event: usize,
+        settings: DrawSettings,
+        state_size: usize,
+    ) -> Option<(usize, &FaultVisState)> {
+        match self.sets.iter().position(|set| set.settings == settings) {
+            Some(idx) => self.sets[..=idx].rotate_right(1),
+            None => {
+                self.sets.truncate(MAX_SETS - 1);
+                self.sets.insert(
+                    0,
+                    Frames {
+                        settings,
+                        interval: interval(self.num_events, state_size),
+                        frames: Vec::new(),
+                    },
+                );
+            }
+        }
+        let set = &self.sets[0];
+        let count = (event / set.interval).min(set.frames.len());
+        let idx = count.checked_sub(1)?;
+        Some((count * set.interval, &set.frames[idx]))
+    }
+
+    /// The event count at which the next snapshot after `event` is due.
+    pub fn next(&self, event: usize) -> usize {
+        let interval = self.sets.first().map_or(MIN_INTERVAL, |set| set.interval);
+        (event / interval + 1) * interval
+    }
+
+    /// Keep a snapshot of the state after `event` events, if one is due there and isn't already
+    /// taken, with the settings last passed to [`Keyframes::before`].
+    pub fn record(&mut self, event: usize, state: impl FnOnce() -> FaultVisState) {
+        if let Some(set) = self.sets.first_mut()
+            && event == (set.frames.len() + 1) * set.interval
+        {
+            set.frames.push(state());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(color_mode: ColorMode) -> DrawSettings {
+        DrawSettings {
+            color_mode,
+            picked_threads: BTreeSet::new(),
+            hide_threads: false,
+            single_file: None,
+            page_grid: None,
+        }
+    }
+
+    #[test]
+    fn keeps_snapshots_across_settings() {
+        let mut keyframes = Keyframes::new(1 << 20);
+        let interval = MIN_INTERVAL;
+        assert!(keyframes.before(0, settings(ColorMode::Kind), 1).is_none());
+        keyframes.record(interval, FaultVisState::default);
+        keyframes.record(2 * interval, FaultVisState::default);
+
+        // Other settings start over, but switching back finds the snapshots already taken.
+        let at = 3 * interval;
+        assert!(keyframes.before(at, settings(ColorMode::Cpu), 1).is_none());
+        let found = keyframes.before(at, settings(ColorMode::Kind), 1);
+        assert_eq!(found.map(|(done, _)| done), Some(2 * interval));
+
+        // Too many other settings drop the least recently used.
+        for mode in [ColorMode::Cpu, ColorMode::Thread] {
+            keyframes.before(at, settings(mode), 1);
+        }
+        for hide_threads in [true, false] {
+            let mut other = settings(ColorMode::Thread);
+            other.hide_threads = hide_threads;
+            other.picked_threads.insert(1);
+            keyframes.before(at, other, 1);
+        }
+        assert!(keyframes.before(at, settings(ColorMode::Kind), 1).is_none());
+    }
+}
@@ -1,13 +1,27 @@
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::PathBuf,
+};
 
 use crate::app::App;
 use clap::{Parser, Subcommand};
-use perf::EventKind;
 
 pub mod app;
+pub mod breakpoint;
+pub mod container;
 pub mod event;
+pub mod event_log;
+pub mod keyframe;
+pub mod native;
+pub mod page_grid;
 pub mod perf;
+pub mod perf_data;
+pub mod perf_sys;
+pub mod render;
 pub mod single_file_ui;
+pub mod symbols;
+pub mod timeline;
 pub mod trace;
 pub mod ui;
 
@@ -18,29 +32,100 @@ enum PlaybackMode {
     Realtime,
 }
 
-impl ToString for PlaybackMode {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for PlaybackMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             PlaybackMode::FrameStep => "frame-step",
             PlaybackMode::FrameTime => "frame-time",
             PlaybackMode::Realtime => "realtime",
+        })
+    }
+}
+
+#[derive(Parser, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorMode {
+    Kind,
+    Cpu,
+    Thread,
+}
+
+impl ColorMode {
+    /// The next mode, for cycling through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            ColorMode::Kind => ColorMode::Cpu,
+            ColorMode::Cpu => ColorMode::Thread,
+            ColorMode::Thread => ColorMode::Kind,
+        }
+    }
+}
+
+impl Display for ColorMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorMode::Kind => "kind",
+            ColorMode::Cpu => "cpu",
+            ColorMode::Thread => "thread",
+        })
+    }
+}
+
+/// What the height and colour of a file's bars show.
+#[derive(Parser, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BarMode {
+    /// The latest events, fading as later ones come in.
+    Recent,
+    /// How many events each bar has had so far, on a linear scale.
+    Heat,
+    /// How many events each bar has had so far, on a log scale.
+    HeatLog,
+}
+
+impl BarMode {
+    /// The next mode, for cycling through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            BarMode::Recent => BarMode::Heat,
+            BarMode::Heat => BarMode::HeatLog,
+            BarMode::HeatLog => BarMode::Recent,
         }
-        .to_string()
+    }
+}
+
+impl Display for BarMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BarMode::Recent => "recent",
+            BarMode::Heat => "heat",
+            BarMode::HeatLog => "heat-log",
+        })
+    }
+}
+
+#[derive(Parser, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum CaptureBackend {
+    Auto,
+    Native,
+    Perf,
+}
+
+impl Display for CaptureBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CaptureBackend::Auto => "auto",
+            CaptureBackend::Native => "native",
+            CaptureBackend::Perf => "perf",
+        })
     }
 }
 
 #[derive(Parser, Clone, Debug)]
 pub struct PlayCli {
     #[arg(
-        value_name = "FILE",
-        help = "Path of trace file to use (default pfviz.json)"
+        value_name = "TRACE",
+        help = "Trace to use (default pfviz.pfv); older traces can be given by their base name, or their .dat or .json file"
     )]
     trace_file: Option<PathBuf>,
-    #[arg(
-        value_name = "FILE",
-        help = "Path of trace file to use (default pfviz.dat)"
-    )]
-    data_file: Option<PathBuf>,
     #[arg(
         short,
         long,
@@ -59,29 +144,208 @@ pub struct PlayCli {
         default_value_t = 1.0
     )]
     play_speed: f32,
+    #[arg(
+        long,
+        value_name = "CPU",
+        value_delimiter = ',',
+        help = "Only show events from these CPUs (comma separated, can be given multiple times)"
+    )]
+    cpu: Vec<u32>,
+    #[arg(
+        long,
+        value_name = "TID",
+        value_delimiter = ',',
+        help = "Pick out events from these threads, dimming the rest (comma separated, can be given multiple times)"
+    )]
+    tid: Vec<u32>,
+    #[arg(
+        long,
+        help = "Hide events from threads that weren't picked, instead of dimming them"
+    )]
+    hide_others: bool,
+    #[arg(long, help = "Show the log of recent events")]
+    event_log: bool,
+    #[arg(
+        short = 'C',
+        long,
+        help = "What to colour events by",
+        default_value_t = ColorMode::Kind
+    )]
+    color: ColorMode,
+    #[arg(
+        short = 'm',
+        long,
+        help = "What the file bars show",
+        default_value_t = BarMode::Recent
+    )]
+    bar_mode: BarMode,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "How long highlights take to fade to half their height, in trace time",
+        default_value_t = 0.25
+    )]
+    half_life: f64,
+    #[arg(
+        long,
+        help = "What order to show the files in",
+        default_value_t = SortKey::Name
+    )]
+    sort: SortKey,
+    #[arg(
+        long,
+        help = "How to group the files",
+        default_value_t = GroupBy::None
+    )]
+    group: GroupBy,
+}
+
+/// What order the files are shown in.
+#[derive(Parser, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    /// By file name.
+    Name,
+    /// Most faults over the whole trace first.
+    Faults,
+    /// Most cache misses over the whole trace first.
+    Misses,
+    /// Most major faults over the whole trace first.
+    Major,
+    /// Largest mapping first.
+    Size,
+    /// Most recently faulted on first, as of the current event.
+    Recent,
+}
+
+impl SortKey {
+    /// The next key, for cycling through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            SortKey::Name => SortKey::Faults,
+            SortKey::Faults => SortKey::Misses,
+            SortKey::Misses => SortKey::Major,
+            SortKey::Major => SortKey::Size,
+            SortKey::Size => SortKey::Recent,
+            SortKey::Recent => SortKey::Name,
+        }
+    }
+}
+
+impl Display for SortKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SortKey::Name => "name",
+            SortKey::Faults => "faults",
+            SortKey::Misses => "misses",
+            SortKey::Major => "major",
+            SortKey::Size => "size",
+            SortKey::Recent => "recent",
+        })
+    }
+}
+
+/// How the files are grouped, with each group starting a new row.
+#[derive(Parser, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    None,
+    /// By the directory the file is in.
+    Dir,
+    /// Shared libraries, data files and anonymous memory.
+    Type,
+}
+
+impl GroupBy {
+    /// The next grouping, for cycling through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            GroupBy::None => GroupBy::Dir,
+            GroupBy::Dir => GroupBy::Type,
+            GroupBy::Type => GroupBy::None,
+        }
+    }
+}
+
+impl Display for GroupBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GroupBy::None => "none",
+            GroupBy::Dir => "dir",
+            GroupBy::Type => "type",
+        })
+    }
+}
+
+#[derive(Parser, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RenderFormat {
+    Cast,
+    Svg,
 }
 
 #[derive(Parser, Clone, Debug)]
-pub struct InfoCli {
+pub struct RenderCli {
+    #[command(flatten)]
+    play: PlayCli,
     #[arg(
-        value_name = "FILE",
-        help = "Path of trace file to use (default pfviz.json)"
+        short,
+        long,
+        help = "File to write: an asciinema cast, or an animated SVG if it ends in .svg"
     )]
-    trace_file: Option<PathBuf>,
+    output: PathBuf,
+    #[arg(long, help = "Output format (default from the output's extension)")]
+    format: Option<RenderFormat>,
+    #[arg(long, help = "Terminal width to render at", default_value_t = 160)]
+    cols: u16,
+    #[arg(long, help = "Terminal height to render at", default_value_t = 48)]
+    rows: u16,
     #[arg(
-        value_name = "FILE",
-        help = "Path of trace file to use (default pfviz.dat)"
+        long,
+        value_name = "EVENT",
+        help = "Start playback at this event (marker A)"
+    )]
+    start: Option<usize>,
+    #[arg(
+        long,
+        value_name = "EVENT",
+        help = "Stop playback at this event (marker B)"
     )]
-    data_file: Option<PathBuf>,
+    end: Option<usize>,
+    #[arg(
+        long,
+        help = "Stop after this many frames, at 30 frames per second",
+        default_value_t = 1800
+    )]
+    max_frames: usize,
+}
+
+#[derive(Parser, Clone, Debug)]
+pub struct InfoCli {
+    #[arg(
+        value_name = "TRACE",
+        help = "Trace to use (default pfviz.pfv); older traces can be given by their base name, or their .dat or .json file"
+    )]
+    trace_file: Option<PathBuf>,
     #[arg(long, short, help = "List all events")]
     list: bool,
     #[arg(long, short, help = "Show stats for each object")]
     stats: bool,
+    #[arg(
+        long,
+        help = "List the code locations that cause the most faults in each object"
+    )]
+    by_ip: bool,
 }
 
+/// Code locations listed for each object by `info --by-ip`.
+const BY_IP_LOCATIONS: usize = 10;
+
 #[derive(Parser, Clone, Debug)]
 pub struct TraceCli {
-    #[arg(short, long, value_name = "FILE", help = "Path of trace file to use")]
+    #[arg(
+        short,
+        long,
+        value_name = "TRACE",
+        help = "Path of the trace to write, .pfv is appended if missing (default pfviz.pfv)"
+    )]
     output: Option<PathBuf>,
     #[arg(
         short,
@@ -90,6 +354,28 @@ pub struct TraceCli {
         help = "Perf event to trace, can be specified multiple times"
     )]
     events: Vec<String>,
+    #[arg(
+        short,
+        long,
+        value_name = "PID",
+        help = "Attach to an already-running process instead of running a command"
+    )]
+    pid: Option<u32>,
+    #[arg(
+        short,
+        long,
+        value_name = "TID",
+        conflicts_with = "pid",
+        help = "Attach to an already-running thread instead of running a command"
+    )]
+    tid: Option<u32>,
+    #[arg(
+        short,
+        long,
+        help = "How to capture events: directly with perf_event_open, or by running perf (auto tries native first)",
+        default_value_t = CaptureBackend::Auto
+    )]
+    backend: CaptureBackend,
     #[arg(
         trailing_var_arg = true,
         allow_hyphen_values = true,
@@ -99,11 +385,36 @@ pub struct TraceCli {
     command: Vec<String>,
 }
 
+#[derive(Parser, Clone, Debug)]
+pub struct ImportCli {
+    #[arg(
+        value_name = "FILE",
+        help = "perf.data file (or saved `perf script` output) to import; perf's symbol names are only read from `perf script` output, otherwise code is symbolized from the binaries on this machine"
+    )]
+    input: PathBuf,
+    #[arg(
+        short,
+        long,
+        value_name = "TRACE",
+        help = "Path of the trace to write, .pfv is appended if missing (default pfviz.pfv)"
+    )]
+    output: Option<PathBuf>,
+    #[arg(
+        short,
+        long = "event",
+        value_name = "EVENT",
+        help = "Kind of an extra perf event in the input, as for trace; can be specified multiple times"
+    )]
+    events: Vec<String>,
+}
+
 #[derive(Clone, Debug, Subcommand)]
 enum SubCmd {
     Play(PlayCli),
     Trace(TraceCli),
+    Import(ImportCli),
     Info(InfoCli),
+    Render(RenderCli),
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -120,32 +431,50 @@ fn main() -> color_eyre::Result<()> {
 
     let cli = Cli::parse();
 
-    ctrlc::set_handler(|| {}).unwrap();
+    ctrlc::set_handler(trace::interrupt).unwrap();
 
-    let result = match cli.sub_cmd {
+    match cli.sub_cmd {
         SubCmd::Play(play_cli) => {
-            let jsonfile = play_cli.trace_file.clone().unwrap_or("pfviz.json".into());
-            let datafile = play_cli.data_file.clone().unwrap_or("pfviz.dat".into());
-            let data = perf::FaultData::open(datafile, jsonfile)?;
-            let terminal = ratatui::init();
+            let data =
+                perf::FaultData::open(play_cli.trace_file.as_deref().unwrap_or("pfviz".as_ref()))?;
             let app = App::new(play_cli, data);
+            let terminal = ratatui::init();
             let result = app.run(terminal);
             ratatui::restore();
             result
         }
+        SubCmd::Render(render_cli) => render::render(&render_cli),
         SubCmd::Trace(trace_cli) => trace::trace(&trace_cli),
+        SubCmd::Import(import_cli) => trace::import(&import_cli),
         SubCmd::Info(info_cli) => {
-            let jsonfile = info_cli.trace_file.clone().unwrap_or("pfviz.json".into());
-            let datafile = info_cli.data_file.clone().unwrap_or("pfviz.dat".into());
-            let data = perf::FaultData::open(&datafile, &jsonfile)?;
+            let trace_file = info_cli.trace_file.unwrap_or("pfviz".into());
+            let data = perf::FaultData::open(&trace_file)?;
 
             println!(
-                "{} ({}): {} objects, {} events",
-                jsonfile.display(),
-                datafile.display(),
+                "{}: {} objects, {} events",
+                trace_file.display(),
                 data.json.objects.len(),
                 data.records.slice().len()
             );
+            let meta = &data.meta;
+            if !meta.source.is_empty() {
+                println!(
+                    "captured by pfviz {} ({}) at {}s since epoch",
+                    meta.pfviz_version, meta.source, meta.created
+                );
+                if let Some(input) = &meta.input {
+                    println!("imported from {}", input.display());
+                }
+                if let Some(pid) = meta.pid.or(meta.tid) {
+                    println!("attached to {}", pid);
+                }
+                if !meta.command.is_empty() {
+                    println!("command: {}", meta.command.join(" "));
+                }
+                if !meta.events.is_empty() {
+                    println!("events: {}", meta.events.join(" "));
+                }
+            }
             println!("objects:");
             let mut v = vec![];
             let mut event_map = HashMap::new();
@@ -158,7 +487,7 @@ fn main() -> color_eyre::Result<()> {
                 }
             }
 
-            v.sort_by(|a, b| a.0.1.faults.cmp(&b.0.1.faults));
+            v.sort_by_key(|a| a.0.1.faults);
 
             for obj in v {
                 println!("{:4}: {} {}", obj.0.0, obj.0.1.faults, obj.1);
@@ -166,15 +495,94 @@ fn main() -> color_eyre::Result<()> {
                     let events = event_map.get(obj.0.0).unwrap();
                     let misses = events.iter().filter(|e| e.kind().is_miss()).count();
                     let faults = events.iter().filter(|e| e.kind().is_fault()).count();
-                    println!("      {} misses, {} faults", misses, faults);
+                    let access = |write| {
+                        events
+                            .iter()
+                            .filter(|e| e.kind().is_fault() && e.was_write() == write)
+                            .count()
+                    };
+                    let (reads, writes) = (access(Some(false)), access(Some(true)));
+                    print!(
+                        "      {} misses, {} faults ({} reads, {} writes",
+                        misses, faults, reads, writes
+                    );
+                    if reads + writes < faults {
+                        print!(", {} unknown", faults - reads - writes);
+                    }
+                    println!(")");
                 }
             }
 
+            if info_cli.by_ip {
+                // Instructions at the same function and line are counted together.
+                let mut locations: HashMap<usize, HashMap<String, usize>> = HashMap::new();
+                for event in data.records.slice().iter().filter(|e| e.kind().is_fault()) {
+                    *locations
+                        .entry(event.obj_id())
+                        .or_default()
+                        .entry(data.code_location(event).to_string())
+                        .or_default() += 1;
+                }
+                let mut locations = locations.into_iter().collect::<Vec<_>>();
+                locations.sort_by_cached_key(|(obj, sites)| {
+                    (std::cmp::Reverse(sites.values().sum::<usize>()), *obj)
+                });
+                println!("faults by code location:");
+                for (obj, sites) in locations {
+                    let total = sites.values().sum::<usize>();
+                    let name = data
+                        .json
+                        .objects
+                        .get(&obj)
+                        .and_then(|object| data.json.strings.resolve(object.file));
+                    println!(
+                        "{:4}: {} faults {}",
+                        obj,
+                        total,
+                        name.unwrap_or("[unknown]")
+                    );
+                    let mut sites = sites.into_iter().collect::<Vec<_>>();
+                    sites.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                    for (site, faults) in sites.iter().take(BY_IP_LOCATIONS) {
+                        println!(
+                            "      {:8} {:5.1}% {}",
+                            faults,
+                            *faults as f64 * 100.0 / total as f64,
+                            site
+                        );
+                    }
+                    if sites.len() > BY_IP_LOCATIONS {
+                        println!("      ... {} more", sites.len() - BY_IP_LOCATIONS);
+                    }
+                }
+            }
+
+            let mut cpus = BTreeMap::new();
+            for event in data.records.slice() {
+                let (faults, misses) = cpus.entry(event.cpu()).or_insert((0, 0));
+                if event.kind().is_miss() {
+                    *misses += 1;
+                } else {
+                    *faults += 1;
+                }
+            }
+            println!("cpus:");
+            for (cpu, (faults, misses)) in cpus {
+                println!(
+                    "{:4}: {} events ({} faults, {} misses)",
+                    cpu,
+                    faults + misses,
+                    faults,
+                    misses
+                );
+            }
+
             if info_cli.list {
                 for event in data.records.slice() {
                     println!(
-                        "{:?}: {:?} at {:x}: {}",
+                        "{:?}: cpu {} {:?} at {:x}: {}",
                         event.time(),
+                        event.cpu(),
                         event.kind(),
                         event.offset(),
                         data.json
@@ -187,7 +595,5 @@ fn main() -> color_eyre::Result<()> {
 
             Ok(())
         }
-    };
-
-    result
+    }
 }
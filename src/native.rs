@@ -0,0 +1,820 @@
+//! Capture backend that opens events directly with perf_event_open(2) and reads the kernel's ring
+//! buffers, instead of running `perf record` and reparsing `perf script` output.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::File,
+    io::Write,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{OptionExt, Result, bail, eyre};
+
+use crate::{
+    TraceCli,
+    perf::{
+        self, EventKind, Interner, MMap, MapChange, MapEvent, PerfData, PerfEvent, Resolver,
+        SpilledRecords, Timestamp,
+    },
+    perf_sys::{self, PerfEventAttr},
+};
+
+/// Number of data pages in each per-CPU ring buffer (must be a power of two).
+const DATA_PAGES: usize = 512;
+
+/// What every sample records. The identifier comes first so that samples can be matched to their
+/// event before decoding the rest.
+const SAMPLE_TYPE: u64 = perf_sys::PERF_SAMPLE_IDENTIFIER
+    | perf_sys::PERF_SAMPLE_IP
+    | perf_sys::PERF_SAMPLE_TID
+    | perf_sys::PERF_SAMPLE_TIME
+    | perf_sys::PERF_SAMPLE_ADDR
+    | perf_sys::PERF_SAMPLE_CPU;
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn last_os_error(what: &str) -> color_eyre::Report {
+    eyre!("{}: {}", what, std::io::Error::last_os_error())
+}
+
+/// Parse a list like `0-3,8,10-11`, as found in `/sys/devices/system/cpu/online`.
+fn parse_cpu_list(list: &str) -> Result<Vec<i32>> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((lo, hi)) => cpus.extend(lo.parse::<i32>()?..=hi.parse::<i32>()?),
+            None => cpus.push(part.parse()?),
+        }
+    }
+    Ok(cpus)
+}
+
+fn online_cpus() -> Result<Vec<i32>> {
+    parse_cpu_list(&std::fs::read_to_string("/sys/devices/system/cpu/online")?)
+}
+
+/// Place `value` into the config words according to a sysfs PMU format string such as
+/// `config:0-7` or `config1:0-15,32-35`.
+fn apply_format(attr: &mut PerfEventAttr, format: &str, mut value: u64) -> Result<()> {
+    let (field, bits) = format
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| eyre!("invalid PMU format `{}'", format))?;
+    let config = match field {
+        "config" => &mut attr.config,
+        "config1" => &mut attr.config1,
+        "config2" => &mut attr.config2,
+        _ => bail!("unsupported PMU format field `{}'", field),
+    };
+    for range in bits.split(',') {
+        let (lo, hi) = match range.split_once('-') {
+            Some((lo, hi)) => (lo.parse::<u32>()?, hi.parse::<u32>()?),
+            None => (range.parse::<u32>()?, range.parse::<u32>()?),
+        };
+        if hi < lo || hi > 63 {
+            bail!(
+                "invalid bit range `{}' in PMU format `{}'",
+                range,
+                format.trim()
+            );
+        }
+        let mask = u64::MAX >> (63 - (hi - lo));
+        *config |= (value & mask) << lo;
+        value = value.checked_shr(hi - lo + 1).unwrap_or(0);
+    }
+    Ok(())
+}
+
+/// Resolve a `pmu/term,term=value/` event using the PMU's sysfs description.
+fn parse_pmu_event(attr: &mut PerfEventAttr, pmu: &str, terms: &str) -> Result<()> {
+    let dir = Path::new("/sys/bus/event_source/devices").join(pmu);
+    attr.kind = std::fs::read_to_string(dir.join("type"))
+        .map_err(|e| eyre!("unknown PMU `{}': {}", pmu, e))?
+        .trim()
+        .parse()?;
+    for term in terms.split(',').filter(|t| !t.is_empty()) {
+        match term.split_once('=') {
+            Some((key, value)) => {
+                let value = match value.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16)?,
+                    None => value.parse()?,
+                };
+                match key {
+                    "config" => attr.config = value,
+                    "config1" => attr.config1 = value,
+                    "config2" => attr.config2 = value,
+                    _ => {
+                        let format = std::fs::read_to_string(dir.join("format").join(key))
+                            .map_err(|_| eyre!("PMU `{}' has no term `{}'", pmu, key))?;
+                        apply_format(attr, &format, value)?;
+                    }
+                }
+            }
+            None => {
+                // A named event, which sysfs describes in terms of the format fields.
+                let alias = std::fs::read_to_string(dir.join("events").join(term))
+                    .map_err(|_| eyre!("PMU `{}' has no event `{}'", pmu, term))?;
+                parse_pmu_event(attr, pmu, alias.trim())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn tracepoint_id(name: &str) -> Result<u64> {
+    let (system, event) = name
+        .split_once(':')
+        .ok_or_eyre(format!("tracepoint `{}' should be SYSTEM:EVENT", name))?;
+    for root in ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"] {
+        let path = Path::new(root)
+            .join("events")
+            .join(system)
+            .join(event)
+            .join("id");
+        if let Ok(id) = std::fs::read_to_string(path) {
+            return Ok(id.trim().parse()?);
+        }
+    }
+    bail!("tracepoint `{}' not found in tracefs", name)
+}
+
+/// Translate a perf-style event name into an attr. Only the forms that can be resolved without
+/// perf's event tables are supported: generic hardware and software events, raw `rNNNN` events,
+/// `pmu/.../` events described in sysfs, and tracepoints.
+pub fn parse_event(spec: &str) -> Result<PerfEventAttr> {
+    let mut attr = PerfEventAttr::default();
+    let (name, modifiers) = match spec.rsplit_once(':') {
+        Some((name, mods)) if !mods.is_empty() && mods.chars().all(|c| "ukhpPGHS".contains(c)) => {
+            (name, mods)
+        }
+        _ => (spec, ""),
+    };
+    let precise = modifiers.chars().filter(|c| *c == 'p').count() as u64;
+    attr.flags |= precise.min(3) << perf_sys::ATTR_PRECISE_IP_SHIFT;
+
+    let (kind, config) = match name {
+        "page-faults" | "faults" => (perf_sys::PERF_TYPE_SOFTWARE, 2),
+        "minor-faults" => (
+            perf_sys::PERF_TYPE_SOFTWARE,
+            perf_sys::PERF_COUNT_SW_PAGE_FAULTS_MIN,
+        ),
+        "major-faults" => (
+            perf_sys::PERF_TYPE_SOFTWARE,
+            perf_sys::PERF_COUNT_SW_PAGE_FAULTS_MAJ,
+        ),
+        "cpu-cycles" | "cycles" => (perf_sys::PERF_TYPE_HARDWARE, 0),
+        "instructions" => (perf_sys::PERF_TYPE_HARDWARE, 1),
+        "cache-references" => (perf_sys::PERF_TYPE_HARDWARE, 2),
+        "cache-misses" => (perf_sys::PERF_TYPE_HARDWARE, 3),
+        "branch-instructions" | "branches" => (perf_sys::PERF_TYPE_HARDWARE, 4),
+        "branch-misses" => (perf_sys::PERF_TYPE_HARDWARE, 5),
+        "bus-cycles" => (perf_sys::PERF_TYPE_HARDWARE, 6),
+        _ => {
+            if let Some(raw) = name.strip_prefix('r')
+                && let Ok(config) = u64::from_str_radix(raw, 16)
+            {
+                (perf_sys::PERF_TYPE_RAW, config)
+            } else if let Some((pmu, terms)) = name.split_once('/') {
+                parse_pmu_event(&mut attr, pmu, terms.trim_end_matches('/'))?;
+                (attr.kind, attr.config)
+            } else if name.contains(':') {
+                (perf_sys::PERF_TYPE_TRACEPOINT, tracepoint_id(name)?)
+            } else {
+                bail!("event `{}' is not supported by the native backend", spec);
+            }
+        }
+    };
+    attr.kind = kind;
+    attr.config = config;
+    Ok(attr)
+}
+
+fn perf_event_open(attr: &PerfEventAttr, pid: i32, cpu: i32, name: &str) -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            pid,
+            cpu,
+            -1,
+            perf_sys::PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(last_os_error(&format!(
+            "perf_event_open for `{}' on cpu {} failed",
+            name, cpu
+        )));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// A mapped per-CPU ring buffer.
+struct RingBuffer {
+    base: *mut u8,
+    len: usize,
+    data_size: usize,
+    page_size: usize,
+    scratch: Vec<u8>,
+}
+
+impl RingBuffer {
+    fn new(fd: RawFd) -> Result<Self> {
+        let page_size = page_size();
+        let len = (DATA_PAGES + 1) * page_size;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(last_os_error("failed to map perf ring buffer"));
+        }
+        Ok(Self {
+            base: base.cast(),
+            len,
+            data_size: DATA_PAGES * page_size,
+            page_size,
+            scratch: Vec::new(),
+        })
+    }
+
+    fn control(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*self.base.add(offset).cast::<AtomicU64>() }
+    }
+
+    /// Copy `len` bytes starting at ring position `pos` into the scratch buffer, handling wrap.
+    fn copy_out(&mut self, pos: u64, len: usize) {
+        let data =
+            unsafe { std::slice::from_raw_parts(self.base.add(self.page_size), self.data_size) };
+        let start = (pos % self.data_size as u64) as usize;
+        let first = len.min(self.data_size - start);
+        self.scratch.clear();
+        self.scratch
+            .extend_from_slice(&data[start..(start + first)]);
+        self.scratch.extend_from_slice(&data[..(len - first)]);
+    }
+
+    /// Pass every complete record currently in the buffer to `f` as (header, body), then release
+    /// the space back to the kernel.
+    fn drain(&mut self, mut f: impl FnMut(&perf_sys::RecordHeader, &[u8])) {
+        let head = self
+            .control(perf_sys::MMAP_PAGE_DATA_HEAD)
+            .load(Ordering::Acquire);
+        let mut tail = self
+            .control(perf_sys::MMAP_PAGE_DATA_TAIL)
+            .load(Ordering::Relaxed);
+        while tail < head {
+            self.copy_out(tail, size_of::<perf_sys::RecordHeader>());
+            let header: perf_sys::RecordHeader = bytemuck::pod_read_unaligned(&self.scratch);
+            let size = header.size as usize;
+            if size < size_of::<perf_sys::RecordHeader>() {
+                tracing::warn!("corrupt record in perf ring buffer, dropping the rest");
+                tail = head;
+                break;
+            }
+            self.copy_out(tail, size);
+            f(
+                &header,
+                &self.scratch[size_of::<perf_sys::RecordHeader>()..],
+            );
+            tail += size as u64;
+        }
+        self.control(perf_sys::MMAP_PAGE_DATA_TAIL)
+            .store(tail, Ordering::Release);
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base.cast(), self.len) };
+    }
+}
+
+/// A child process forked for tracing, held just before exec until the events are open.
+struct GatedChild {
+    pid: libc::pid_t,
+    gate: Option<OwnedFd>,
+    status: Option<i32>,
+}
+
+impl GatedChild {
+    fn spawn(command: &[String]) -> Result<Self> {
+        let args = command
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+        argv.push(std::ptr::null());
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(last_os_error("failed to create pipe"));
+        }
+        let (read_end, write_end) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(last_os_error("fork failed"));
+        }
+        if pid == 0 {
+            // Only async-signal-safe calls from here on.
+            unsafe {
+                libc::close(write_end.as_raw_fd());
+                let mut byte = 0u8;
+                if libc::read(read_end.as_raw_fd(), (&raw mut byte).cast(), 1) != 1 {
+                    libc::_exit(1);
+                }
+                libc::execvp(argv[0], argv.as_ptr());
+                libc::_exit(127);
+            }
+        }
+        drop(read_end);
+        Ok(Self {
+            pid,
+            gate: Some(write_end),
+            status: None,
+        })
+    }
+
+    fn release(&mut self) -> Result<()> {
+        if let Some(gate) = self.gate.take() {
+            File::from(gate).write_all(b"x")?;
+        }
+        Ok(())
+    }
+
+    fn try_wait(&mut self) -> Option<i32> {
+        if self.status.is_none() {
+            let mut status = 0;
+            let ret = unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) };
+            if ret == self.pid || ret < 0 {
+                self.status = Some(status);
+            }
+        }
+        self.status
+    }
+}
+
+impl Drop for GatedChild {
+    fn drop(&mut self) {
+        if self.gate.take().is_some() {
+            // Never released: closing the gate makes the child exit without running anything.
+            unsafe { libc::waitpid(self.pid, std::ptr::null_mut(), 0) };
+        }
+    }
+}
+
+enum Target {
+    Child(GatedChild),
+    Attached(libc::pid_t),
+}
+
+fn threads_of(pid: u32) -> Result<Vec<i32>> {
+    let mut threads = Vec::new();
+    for entry in std::fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|t| t.parse().ok()) {
+            threads.push(tid);
+        }
+    }
+    Ok(threads)
+}
+
+/// Make room for one descriptor per (thread, CPU, event).
+fn raise_fd_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        }
+    }
+}
+
+/// An open set of events, ready to start collecting.
+pub struct Capture {
+    target: Target,
+    _fds: Vec<OwnedFd>,
+    buffers: Vec<RingBuffer>,
+    poll_fds: Vec<RawFd>,
+    /// Event name and sample_type for each event id.
+    ids: HashMap<u64, (u32, u64)>,
+    strings: Interner,
+    initial_maps: Vec<MapEvent>,
+}
+
+impl Capture {
+    /// Open the page-fault events plus any user-supplied events on the target. For a command, this
+    /// forks the child but doesn't let it run until [`Capture::run`].
+    pub fn open(cli: &TraceCli, ev_map: &HashMap<&str, EventKind>) -> Result<Self> {
+        let mut strings = Interner::default();
+        let mut events = Vec::new();
+        for name in ["major-faults", "minor-faults"]
+            .into_iter()
+            .chain(ev_map.keys().copied())
+        {
+            events.push((strings.get_or_intern(name), name, parse_event(name)?));
+        }
+
+        let cpus = online_cpus()?;
+        let (target, threads, initial_maps) = match cli.pid.or(cli.tid) {
+            Some(pid) => {
+                let threads = if cli.pid.is_some() {
+                    threads_of(pid)?
+                } else {
+                    vec![pid as i32]
+                };
+                let maps = perf::read_proc_maps(pid)?;
+                (
+                    Target::Attached(pid as libc::pid_t),
+                    threads,
+                    perf::intern_proc_maps(&maps, &mut strings),
+                )
+            }
+            None => {
+                let child = GatedChild::spawn(&cli.command)?;
+                let pid = child.pid;
+                (Target::Child(child), vec![pid], Vec::new())
+            }
+        };
+
+        let child = matches!(target, Target::Child(_));
+        let configure = |first: bool, mut attr: PerfEventAttr| {
+            attr.size = size_of::<PerfEventAttr>() as u32;
+            attr.sample_period = 1;
+            attr.sample_type = SAMPLE_TYPE;
+            attr.flags |= perf_sys::ATTR_FLAG_INHERIT
+                | perf_sys::ATTR_FLAG_EXCLUDE_HV
+                | perf_sys::ATTR_FLAG_SAMPLE_ID_ALL
+                | perf_sys::ATTR_FLAG_WATERMARK;
+            if attr.kind == perf_sys::PERF_TYPE_TRACEPOINT {
+                // Tracepoints fire in the kernel, so excluding it would filter out every sample.
+                attr.sample_type |= perf_sys::PERF_SAMPLE_RAW;
+            } else {
+                attr.flags |= perf_sys::ATTR_FLAG_EXCLUDE_KERNEL;
+            }
+            attr.wakeup_watermark = (DATA_PAGES * page_size() / 4) as u32;
+            if first {
+                // Mapping and task records are per task, so one event per thread is enough.
+                attr.flags |= perf_sys::ATTR_FLAG_MMAP
+                    | perf_sys::ATTR_FLAG_MMAP_DATA
+                    | perf_sys::ATTR_FLAG_MMAP2
+                    | perf_sys::ATTR_FLAG_COMM
+                    | perf_sys::ATTR_FLAG_COMM_EXEC
+                    | perf_sys::ATTR_FLAG_TASK;
+            }
+            if child {
+                attr.flags |= perf_sys::ATTR_FLAG_DISABLED | perf_sys::ATTR_FLAG_ENABLE_ON_EXEC;
+            }
+            attr
+        };
+
+        // Some of what we want to know is only available from tracepoints, which usually need more
+        // privilege than the fault events. Use them if we can.
+        for (tracepoint, without) in [
+            (
+                perf_sys::MUNMAP_TRACEPOINT,
+                "unmapped ranges are only noticed once remapped",
+            ),
+            (
+                perf_sys::PAGE_FAULT_TRACEPOINT,
+                "faults won't be split into reads and writes",
+            ),
+        ] {
+            let opened = parse_event(tracepoint).and_then(|attr| {
+                let probe = configure(false, attr);
+                perf_event_open(&probe, threads[0], cpus[0], tracepoint).map(|_| attr)
+            });
+            match opened {
+                Ok(attr) => events.push((strings.get_or_intern(tracepoint), tracepoint, attr)),
+                Err(e) => tracing::info!("not tracing {} ({}), {}", tracepoint, e, without),
+            }
+        }
+
+        raise_fd_limit();
+        let mut fds = Vec::new();
+        let mut buffers = Vec::new();
+        let mut poll_fds = Vec::new();
+        let mut ids = HashMap::new();
+        for cpu in cpus {
+            let mut leader: Option<RawFd> = None;
+            for thread in &threads {
+                for (i, (name_id, name, attr)) in events.iter().enumerate() {
+                    let attr = configure(i == 0, *attr);
+                    let fd = perf_event_open(&attr, *thread, cpu, name)?;
+                    match leader {
+                        None => {
+                            buffers.push(RingBuffer::new(fd.as_raw_fd())?);
+                            poll_fds.push(fd.as_raw_fd());
+                            leader = Some(fd.as_raw_fd());
+                        }
+                        Some(leader) => {
+                            let ret = unsafe {
+                                libc::ioctl(
+                                    fd.as_raw_fd(),
+                                    perf_sys::PERF_EVENT_IOC_SET_OUTPUT as _,
+                                    leader,
+                                )
+                            };
+                            if ret != 0 {
+                                return Err(last_os_error("failed to redirect perf output"));
+                            }
+                        }
+                    }
+                    let mut id = 0u64;
+                    if unsafe {
+                        libc::ioctl(
+                            fd.as_raw_fd(),
+                            perf_sys::PERF_EVENT_IOC_ID as _,
+                            &mut id as *mut u64,
+                        )
+                    } != 0
+                    {
+                        return Err(last_os_error("failed to read perf event id"));
+                    }
+                    ids.insert(id, (*name_id, attr.sample_type));
+                    fds.push(fd);
+                }
+            }
+        }
+
+        Ok(Self {
+            target,
+            _fds: fds,
+            buffers,
+            poll_fds,
+            ids,
+            strings,
+            initial_maps,
+        })
+    }
+
+    /// Start the target (if we launched it) and collect until it exits or we're interrupted.
+    /// Events are resolved as they come in and their records written to `records`, so that a
+    /// long capture isn't held in memory.
+    pub fn run(mut self, ev_map: &HashMap<&str, EventKind>, records: File) -> Result<PerfData> {
+        let mut resolver = Resolver::new(std::mem::take(&mut self.strings), ev_map);
+        let mut records = SpilledRecords::new(records);
+        let unknown = resolver.strings().get_or_intern("[unknown]");
+        let munmap = resolver
+            .strings()
+            .get_or_intern(perf_sys::MUNMAP_TRACEPOINT);
+        let page_fault = resolver
+            .strings()
+            .get_or_intern(perf_sys::PAGE_FAULT_TRACEPOINT);
+        // Records waiting to be resolved in time order, which the per-CPU buffers don't give.
+        let mut events = Vec::new();
+        let mut maps = std::mem::take(&mut self.initial_maps);
+        // The newest time seen in the previous pass over the buffers, and in this one.
+        let mut watermark = Duration::ZERO;
+        let mut newest = Duration::ZERO;
+        let mut lost = 0u64;
+
+        match &mut self.target {
+            Target::Child(child) => child.release()?,
+            Target::Attached(_) => tracing::info!("attached, press Ctrl-C to stop tracing"),
+        }
+
+        let mut pollfds = self
+            .poll_fds
+            .iter()
+            .map(|fd| libc::pollfd {
+                fd: *fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+        let mut done = false;
+        let mut last_report = (Instant::now(), 0);
+        while !done {
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, 10) };
+            // A child that ignores SIGINT keeps running, but Ctrl-C still stops the trace.
+            done = crate::trace::interrupted()
+                || match &mut self.target {
+                    Target::Child(child) => child.try_wait().is_some(),
+                    Target::Attached(pid) => (unsafe { libc::kill(*pid, 0) }) != 0,
+                };
+
+            let strings = resolver.strings();
+            let ids = &self.ids;
+            let record_time = |body: &[u8]| {
+                perf_sys::record_identifier(body)
+                    .and_then(|id| ids.get(&id))
+                    .and_then(|(_, sample_type)| perf_sys::parse_sample_id(body, *sample_type))
+                    .map_or(0, |id| id.time)
+            };
+            for buffer in &mut self.buffers {
+                buffer.drain(|header, body| match header.kind {
+                    perf_sys::PERF_RECORD_SAMPLE => {
+                        let Some((name, sample_type)) = perf_sys::Cursor::new(body)
+                            .u64()
+                            .and_then(|id| ids.get(&id))
+                        else {
+                            return;
+                        };
+                        let Some(sample) = perf_sys::parse_sample(body, *sample_type) else {
+                            return;
+                        };
+                        if *name == munmap {
+                            if let Some((addr, len)) = perf_sys::parse_munmap(sample.raw) {
+                                maps.push(MapEvent {
+                                    pid: sample.pid,
+                                    time: Timestamp::from_nanos(sample.time),
+                                    change: MapChange::Unmap { addr, len },
+                                });
+                            }
+                            return;
+                        }
+                        let (addr, write) = if *name == page_fault {
+                            match perf_sys::parse_page_fault(sample.raw) {
+                                Some((addr, error_code)) => {
+                                    (addr, Some(error_code & perf_sys::PF_WRITE != 0))
+                                }
+                                None => return,
+                            }
+                        } else {
+                            (sample.addr, None)
+                        };
+                        events.push(PerfEvent {
+                            name: *name,
+                            sym: unknown,
+                            addr_sym: unknown,
+                            addr,
+                            ip: sample.ip,
+                            pid: sample.pid,
+                            tid: sample.tid,
+                            cpu: sample.cpu,
+                            write,
+                            time: Timestamp::from_nanos(sample.time),
+                        });
+                    }
+                    perf_sys::PERF_RECORD_MMAP | perf_sys::PERF_RECORD_MMAP2 => {
+                        if let Some(map) = perf_sys::parse_mmap(header.kind, body)
+                            && map.pid != 0
+                            && map.pid != u32::MAX
+                        {
+                            maps.push(MapEvent {
+                                pid: map.pid,
+                                time: Timestamp::from_nanos(record_time(body)),
+                                change: MapChange::Map(MMap {
+                                    file: strings.get_or_intern(map.filename),
+                                    offset: map.pgoff,
+                                    addr: map.addr,
+                                    len: map.len,
+                                }),
+                            });
+                        }
+                    }
+                    perf_sys::PERF_RECORD_FORK => {
+                        if let Some(fork) = perf_sys::parse_fork(body)
+                            && fork.pid != fork.ppid
+                        {
+                            maps.push(MapEvent {
+                                pid: fork.pid,
+                                time: Timestamp::from_nanos(fork.time),
+                                change: MapChange::Fork { parent: fork.ppid },
+                            });
+                        }
+                    }
+                    perf_sys::PERF_RECORD_COMM
+                        if header.misc & perf_sys::PERF_RECORD_MISC_COMM_EXEC != 0 =>
+                    {
+                        if let Some(pid) = perf_sys::Cursor::new(body).u32() {
+                            maps.push(MapEvent {
+                                pid,
+                                time: Timestamp::from_nanos(record_time(body)),
+                                change: MapChange::Exec,
+                            });
+                        }
+                    }
+                    perf_sys::PERF_RECORD_LOST => {
+                        let mut cur = perf_sys::Cursor::new(body);
+                        cur.skip(8);
+                        lost += cur.u64().unwrap_or(0);
+                    }
+                    _ => {}
+                });
+            }
+
+            // Anything older than the newest record of the previous pass was already in its
+            // buffer when this pass began, so it has been read by now and can be resolved, the
+            // same way perf orders its per-CPU buffers by rounds.
+            newest = events
+                .iter()
+                .map(|event: &PerfEvent| event.time.into())
+                .chain(maps.iter().map(|map: &MapEvent| map.time.into()))
+                .fold(newest, Duration::max);
+            let limit = if done { Duration::MAX } else { watermark };
+            resolver.resolve(
+                take_until(&mut events, limit, |event| event.time.into()),
+                take_until(&mut maps, limit, |map| map.time.into()),
+                |fault| records.push(&fault),
+            )?;
+            watermark = newest;
+
+            if last_report.0.elapsed() >= Duration::from_secs(1) && records.count() != last_report.1
+            {
+                tracing::info!("captured {} events so far", records.count());
+                last_report = (Instant::now(), records.count());
+            }
+        }
+
+        if let Target::Child(child) = &mut self.target
+            && let Some(status) = child.try_wait()
+            && libc::WIFEXITED(status)
+            && libc::WEXITSTATUS(status) != 0
+        {
+            tracing::warn!(
+                "traced command exited with status {}",
+                libc::WEXITSTATUS(status)
+            );
+        }
+        if lost > 0 {
+            tracing::warn!(
+                "kernel dropped {} records because the ring buffer was full",
+                lost
+            );
+        }
+
+        tracing::info!("captured {} events", records.count());
+        records.flush()?;
+        let mut pd = resolver.finish();
+        pd.spilled = Some(records);
+        Ok(pd)
+    }
+}
+
+/// Take the items at or before `time` out of `pending`.
+fn take_until<T>(pending: &mut Vec<T>, time: Duration, key: impl Fn(&T) -> Duration) -> Vec<T> {
+    pending.sort_by_key(&key);
+    let ready = pending.partition_point(|item| key(item) <= time);
+    pending.drain(..ready).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0\n").unwrap(), [0]);
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11]
+        );
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+
+    #[test]
+    fn pmu_formats() {
+        let mut attr = PerfEventAttr::default();
+        apply_format(&mut attr, "config:0-7", 0x1ab).unwrap();
+        assert_eq!(attr.config, 0xab);
+        // The value's bits are spread over the ranges in order.
+        apply_format(&mut attr, "config1:0-3,8-11\n", 0x5a).unwrap();
+        assert_eq!(attr.config1, 0x50a);
+        apply_format(&mut attr, "config2:63", 1).unwrap();
+        assert_eq!(attr.config2, 1 << 63);
+        assert!(apply_format(&mut attr, "period:0-7", 1).is_err());
+        assert!(apply_format(&mut attr, "config", 1).is_err());
+        assert!(apply_format(&mut attr, "config:7-0", 1).is_err());
+        assert!(apply_format(&mut attr, "config:60-64", 1).is_err());
+        apply_format(&mut attr, "config:0-63", u64::MAX).unwrap();
+        assert_eq!(attr.config, u64::MAX);
+    }
+
+    #[test]
+    fn event_specs() {
+        let attr = parse_event("minor-faults").unwrap();
+        assert_eq!(
+            (attr.kind, attr.config),
+            (
+                perf_sys::PERF_TYPE_SOFTWARE,
+                perf_sys::PERF_COUNT_SW_PAGE_FAULTS_MIN
+            )
+        );
+        let attr = parse_event("cache-misses:upp").unwrap();
+        assert_eq!((attr.kind, attr.config), (perf_sys::PERF_TYPE_HARDWARE, 3));
+        assert_eq!(attr.flags >> perf_sys::ATTR_PRECISE_IP_SHIFT & 3, 2);
+        let attr = parse_event("r1a2b").unwrap();
+        assert_eq!((attr.kind, attr.config), (perf_sys::PERF_TYPE_RAW, 0x1a2b));
+        assert!(parse_event("no-such-event").is_err());
+        assert!(tracepoint_id("no-colon").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! A file's pages laid out as a grid filling the screen, two pages to a character cell, for seeing
+//! access patterns like strided scans that a row of bars smears together.
+
+use std::{ops::Range, time::Duration};
+
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::Color,
+    text::Line,
+    widgets::Widget,
+};
+
+use crate::{
+    ColorMode,
+    perf::{EventRecord, PAGE_SIZE},
+    render::rgb,
+    ui::{DIM_COLORS, decay, fault_colors, heat_color},
+};
+
+/// Colour of pages that haven't had an event yet.
+const UNTOUCHED: Color = Color::Rgb(0x30, 0x30, 0x30);
+/// How bright a page stays once its last event has faded, in percent.
+const FADED: u64 = 30;
+
+/// The latest event on one cell of the grid.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PageCell {
+    time: Duration,
+    count: u32,
+    color: Option<Color>,
+}
+
+#[derive(Debug)]
+pub struct PageGrid {
+    objid: usize,
+    name: String,
+    range: Range<u64>,
+    /// Bytes covered by each cell, a power of two number of pages.
+    cell_size: u64,
+    cells: Vec<PageCell>,
+}
+
+/// How the grid's cells are coloured.
+#[derive(Clone, Copy, Debug)]
+pub enum GridScale {
+    /// By the latest event, faded by how long ago it was at `now`.
+    Recent { now: Duration, half_life: Duration },
+    /// By how many events each cell has had.
+    Heat { log: bool },
+}
+
+impl PageGrid {
+    /// Lay out `range` of a file over at most `capacity` cells, one page per cell if they fit.
+    pub fn new(objid: usize, name: String, range: Range<u64>, capacity: usize) -> Self {
+        let pages = (range.end - range.start).div_ceil(PAGE_SIZE);
+        let cell_size = pages.div_ceil(capacity.max(1) as u64).next_power_of_two() * PAGE_SIZE;
+        let cells = (range.end - range.start).div_ceil(cell_size) as usize;
+        Self {
+            objid,
+            name,
+            range,
+            cell_size,
+            cells: vec![PageCell::default(); cells],
+        }
+    }
+
+    pub fn obj_id(&self) -> usize {
+        self.objid
+    }
+
+    /// The file, byte range and cell size shown.
+    pub fn view(&self) -> (usize, Range<u64>, u64) {
+        (self.objid, self.range.clone(), self.cell_size)
+    }
+
+    pub fn fault(&mut self, faults: &[EventRecord], mode: ColorMode, dim: bool) {
+        for fault in faults {
+            if fault.obj_id() != self.objid || !self.range.contains(&fault.offset()) {
+                continue;
+            }
+            let cell =
+                &mut self.cells[((fault.offset() - self.range.start) / self.cell_size) as usize];
+            cell.time = fault.time();
+            cell.count += 1;
+            cell.color = Some(if dim {
+                DIM_COLORS.0
+            } else {
+                fault_colors(fault, false, mode).0
+            });
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cells.fill(PageCell::default());
+    }
+
+    pub fn snapshot(&self) -> Vec<PageCell> {
+        self.cells.clone()
+    }
+
+    pub fn restore(&mut self, cells: &[PageCell]) {
+        self.cells.clone_from_slice(cells);
+    }
+
+    /// Rough size of a snapshot, in bytes.
+    pub fn state_size(&self) -> usize {
+        self.cells.len() * size_of::<PageCell>()
+    }
+
+    fn color(&self, cell: &PageCell, scale: GridScale, max: u32) -> Color {
+        let Some(color) = cell.color else {
+            return UNTOUCHED;
+        };
+        match scale {
+            GridScale::Recent { now, half_life } => {
+                let percent = FADED + decay(100 - FADED, now.saturating_sub(cell.time), half_life);
+                let (r, g, b) = rgb(color).unwrap_or((0xe5, 0xe5, 0xe5));
+                let fade = |c: u8| (c as u64 * percent / 100) as u8;
+                Color::Rgb(fade(r), fade(g), fade(b))
+            }
+            GridScale::Heat { log } => heat_color(cell.count.into(), max.into(), log),
+        }
+    }
+
+    pub fn render(&self, area: Rect, buf: &mut Buffer, scale: GridScale) {
+        let [header, area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(area);
+        let touched = self.cells.iter().filter(|c| c.color.is_some()).count();
+        let max = self.cells.iter().map(|c| c.count).max().unwrap_or(0);
+        let mut text = format!(
+            "{} [{}-{}), {} per cell, {}/{} cells touched",
+            self.name,
+            humansize::format_size(self.range.start, humansize::BINARY),
+            humansize::format_size(self.range.end, humansize::BINARY),
+            humansize::format_size(self.cell_size, humansize::BINARY),
+            touched,
+            self.cells.len()
+        );
+        if let GridScale::Heat { .. } = scale {
+            text += &format!(", up to {} events per cell", max);
+        }
+        let shown = usize::from(area.width) * usize::from(area.height) * 2;
+        if shown < self.cells.len() {
+            text += &format!(", {} cells don't fit", self.cells.len() - shown);
+        }
+        text += ". (p) Close";
+        Line::from(text).render(header, buf);
+
+        // Each character cell shows two rows of the grid, the upper as the foreground of a half
+        // block and the lower as its background.
+        let width = usize::from(area.width).max(1);
+        for (y, pair) in self.cells.chunks(width * 2).enumerate() {
+            if y >= usize::from(area.height) {
+                break;
+            }
+            let (upper, lower) = pair.split_at(width.min(pair.len()));
+            for (x, cell) in upper.iter().enumerate() {
+                let below = lower
+                    .get(x)
+                    .map_or(Color::Reset, |cell| self.color(cell, scale, max));
+                buf[(area.x + x as u16, area.y + y as u16)]
+                    .set_symbol("▀")
+                    .set_fg(self.color(cell, scale, max))
+                    .set_bg(below);
+            }
+        }
+    }
+}
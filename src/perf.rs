@@ -1,17 +1,26 @@
 use std::{
     collections::HashMap,
+    fmt::Display,
     fs::File,
-    io::{BufRead, BufReader, BufWriter, Read, Write},
-    path::Path,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
 use color_eyre::eyre::{Result, bail};
 use memmap2::Mmap;
+use nonoverlapping_interval_tree::NonOverlappingIntervalTree;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use stable_vec::StableVec;
 
+use crate::{
+    container::{self, CaptureMeta},
+    perf_sys,
+    symbols::{self, CodeLocation, CodeSite, ShowLocation},
+};
+
 #[derive(Default, Deserialize, Serialize, Debug, Clone)]
 pub struct Interner {
     map: HashMap<u32, String>,
@@ -57,9 +66,9 @@ impl EventKind {
     }
 }
 
-impl Into<u32> for EventKind {
-    fn into(self) -> u32 {
-        match self {
+impl From<EventKind> for u32 {
+    fn from(value: EventKind) -> u32 {
+        match value {
             EventKind::MajorFault => 1,
             EventKind::MinorFault => 2,
             EventKind::CacheMiss => 3,
@@ -90,23 +99,26 @@ impl From<&str> for EventKind {
     }
 }
 
-impl ToString for EventKind {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             EventKind::MajorFault => "major-fault",
             EventKind::MinorFault => "minor-fault",
             EventKind::CacheMiss => "cache-miss",
             EventKind::Unknown => "unknown",
-        }
-        .to_string()
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct PerfData {
     pub faults: Vec<Event>,
+    /// Records a backend already wrote out as it went, instead of keeping them in `faults`.
+    pub spilled: Option<SpilledRecords>,
     pub objects: StableVec<Object>,
     pub strings: Interner,
+    /// The code the events in `faults` came from, indexed by [`Event::code`].
+    pub code: Vec<CodeLocation>,
 }
 
 impl PerfData {
@@ -121,33 +133,153 @@ pub const PAGE_SIZE: u64 = 0x1000;
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub struct PerfEvent {
-    name: u32,
-    sym: u32,
-    addr_sym: u32,
-    addr: u64,
-    ip: u64,
-    time: Timestamp,
-    tid: u32,
+    pub name: u32,
+    pub sym: u32,
+    pub addr_sym: u32,
+    pub addr: u64,
+    pub ip: u64,
+    pub time: Timestamp,
+    pub pid: u32,
+    pub tid: u32,
+    pub cpu: u32,
+    /// Whether the access was a write, for events that tell us.
+    pub write: Option<bool>,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Timestamp {
-    sec: u64,
-    nsec: u64,
+    pub sec: u64,
+    pub nsec: u64,
 }
 
-impl Into<Duration> for Timestamp {
-    fn into(self) -> Duration {
-        Duration::new(self.sec, self.nsec as u32)
+impl Timestamp {
+    pub fn from_nanos(ns: u64) -> Self {
+        Self {
+            sec: ns / 1_000_000_000,
+            nsec: ns % 1_000_000_000,
+        }
+    }
+}
+
+impl From<Timestamp> for Duration {
+    fn from(value: Timestamp) -> Duration {
+        Duration::new(value.sec, value.nsec as u32)
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct MMap {
-    file: u32,
-    offset: u64,
-    addr: u64,
-    len: u64,
+    pub file: u32,
+    pub offset: u64,
+    pub addr: u64,
+    pub len: u64,
+}
+
+/// A change to a process's address space.
+#[derive(Debug, Clone, Copy)]
+pub enum MapChange {
+    Map(MMap),
+    Unmap {
+        addr: u64,
+        len: u64,
+    },
+    /// The process was forked from `parent`, starting with a copy of its mappings.
+    Fork {
+        parent: u32,
+    },
+    /// The process called exec, dropping all of its mappings.
+    Exec,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MapEvent {
+    pub pid: u32,
+    pub time: Timestamp,
+    pub change: MapChange,
+}
+
+/// The file mappings of one process at some point in time, with the object each maps.
+#[derive(Debug, Default, Clone)]
+struct AddressSpace {
+    tree: NonOverlappingIntervalTree<u64, (usize, MMap)>,
+}
+
+impl AddressSpace {
+    /// Remove `range`, trimming any mappings that only partly overlap it.
+    fn unmap(&mut self, range: Range<u64>) {
+        let overlapping = self
+            .tree
+            .range(range.clone())
+            .filter(|(start, val)| **start < range.end && *val.end() > range.start)
+            .map(|(start, val)| (*start..*val.end(), *val.value()))
+            .collect::<Vec<_>>();
+        for (old, val) in overlapping {
+            self.tree.remove(&old.start);
+            // The pieces keep the original mapping, so offsets within them are unchanged.
+            if old.start < range.start {
+                self.tree.insert(old.start..range.start, val);
+            }
+            if old.end > range.end {
+                self.tree.insert(range.end..old.end, val);
+            }
+        }
+    }
+
+    fn map(&mut self, obj_idx: usize, map: MMap) {
+        let range = map.addr..(map.addr + map.len);
+        self.unmap(range.clone());
+        self.tree.insert(range, (obj_idx, map));
+    }
+
+    fn get(&self, addr: u64) -> Option<&(usize, MMap)> {
+        self.tree.get(&addr)
+    }
+}
+
+/// A file mapping read from `/proc/<pid>/maps`, describing what a process already had mapped
+/// before we attached to it.
+#[derive(Debug, Clone)]
+pub struct ProcMap {
+    /// The process (thread group) the mapping belongs to.
+    pub pid: u32,
+    pub file: String,
+    pub addr: u64,
+    pub len: u64,
+    pub offset: u64,
+}
+
+/// Read the current file-backed mappings of a process (or thread) from procfs.
+pub fn read_proc_maps(pid: u32) -> Result<Vec<ProcMap>> {
+    // Events carry the process id, so mappings are keyed by it even when given a thread.
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let tgid = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+        .unwrap_or(pid);
+    let file = File::open(format!("/proc/{}/maps", pid))?;
+    let mut maps = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // start-end perms offset dev inode [path]
+        let split = line.split_whitespace().collect::<SmallVec<[_; 8]>>();
+        if split.len() < 6 || !split[5].starts_with('/') {
+            // Anonymous mapping or one like [heap] or [vdso], not a file to attribute faults to.
+            continue;
+        }
+        let (start, end) = sscanf::sscanf!(split[0], "{u64:x}-{u64:x}")
+            .map_err(|e| color_eyre::eyre::eyre!(e.to_string()))
+            .inspect_err(|_| tracing::warn!("invalid maps line: {}", line))?;
+        let offset = u64::from_str_radix(split[2], 16)?;
+        maps.push(ProcMap {
+            pid: tgid,
+            file: split[5..].join(" "),
+            addr: start,
+            len: end - start,
+            offset,
+        });
+    }
+    Ok(maps)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -159,35 +291,50 @@ pub struct Object {
     pub biggest_offset: u64,
     pub smallest_offset: u64,
     pub show: bool,
+    /// Length of the largest mapping of the file (0 in older traces).
+    #[serde(default)]
+    pub size: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Event {
     pub obj_idx: usize,
     pub offset: u64,
-    pub was_write: bool,
+    pub was_write: Option<bool>,
     pub time: Duration,
     pub kind: EventKind,
     pub event_name: u32,
     pub addr: u64,
     pub ip: u64,
+    /// Index of the instruction's location in [`PerfData::code`].
+    pub code: Option<u32>,
     pub tid: u32,
+    pub cpu: u32,
 }
 
 pub fn parse_perf_data<Io: Read>(
     reader: BufReader<Io>,
     ev_map: HashMap<&str, EventKind>,
+    initial_maps: &[ProcMap],
 ) -> Result<PerfData> {
     let mut strings = Interner::default();
     let mut events = Vec::new();
-    let mut maps = Vec::new();
+    let mut maps = intern_proc_maps(initial_maps, &mut strings);
     let mut count = 0;
+    // Without a pid on every event, there's no telling processes apart, so all mappings are
+    // treated as belonging to one.
+    let mut have_pids = true;
     tracing::info!("Reading from perf data");
     for line in reader.lines().enumerate() {
         if let Ok(line) = line.1 {
             let split = line.split_whitespace().collect::<SmallVec<[_; 16]>>();
-            let tid = split[0];
-            let tid = u32::from_str_radix(tid, 10)?;
+            let (pid, tid) = match split[0].split_once('/') {
+                Some((pid, tid)) => (pid.parse::<u32>()?, tid.parse::<u32>()?),
+                None => {
+                    have_pids = false;
+                    (0, split[0].parse::<u32>()?)
+                }
+            };
             if tid == 0 {
                 continue;
             }
@@ -195,13 +342,49 @@ pub fn parse_perf_data<Io: Read>(
             if count % 1000 == 0 {
                 eprint!("event: {count}              \r");
             }
-            let _cpu = split[1];
+            let cpu = split[1]
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<u32>()
+                .inspect_err(|_| tracing::warn!("invalid cpu in line: {}", line))
+                .unwrap_or(0);
             let timesplit = split[2].split(".").collect::<SmallVec<[_; 2]>>();
+            // Nanoseconds with --ns, otherwise microseconds.
+            let fraction = &timesplit[1][..(timesplit[1].len() - 1)];
             let time = (
-                u64::from_str_radix(timesplit[0], 10)?,
-                u64::from_str_radix(&timesplit[1][..(timesplit[1].len() - 1)], 10)?,
+                timesplit[0].parse::<u64>()?,
+                format!("{:0<9}", fraction).parse::<u64>()?,
             );
+            let time = Timestamp {
+                sec: time.0,
+                nsec: time.1,
+            };
             let name = split[3];
+            if name.strip_suffix(':') == Some(perf_sys::PAGE_FAULT_TRACEPOINT) {
+                // address=0x... ip=0x... error_code=0x...
+                let field = |key: &str| {
+                    split[4..]
+                        .iter()
+                        .find_map(|f| f.strip_prefix(key)?.strip_prefix("=0x"))
+                        .and_then(|v| u64::from_str_radix(v, 16).ok())
+                };
+                if let (Some(addr), Some(error_code)) = (field("address"), field("error_code")) {
+                    let unknown = strings.get_or_intern("[unknown]");
+                    events.push(PerfEvent {
+                        name: strings.get_or_intern(perf_sys::PAGE_FAULT_TRACEPOINT),
+                        sym: unknown,
+                        addr_sym: unknown,
+                        addr,
+                        ip: 0,
+                        pid,
+                        tid,
+                        cpu,
+                        time,
+                        write: Some(error_code & perf_sys::PF_WRITE != 0),
+                    });
+                }
+                continue;
+            }
             if name == "PERF_RECORD_MMAP" || name == "PERF_RECORD_MMAP2" {
                 let pids = sscanf::sscanf!(split[4], "{i64}/{i64}:")
                     .map_err(|e| color_eyre::eyre::eyre!(e.to_string()))?;
@@ -209,7 +392,7 @@ pub fn parse_perf_data<Io: Read>(
                     continue;
                 }
 
-                if pids.0 != 0 && pids.0 != 0 {
+                if pids.0 != 0 && pids.1 != 0 {
                     let addr = sscanf::sscanf!(split[5], "[{u64:x}({u64:x})")
                         .map_err(|e| color_eyre::eyre::eyre!(e.to_string()))
                         .inspect_err(|_| tracing::warn!("invalid line: {}", line))?;
@@ -217,11 +400,15 @@ pub fn parse_perf_data<Io: Read>(
                         .map_err(|e| color_eyre::eyre::eyre!(e.to_string()))
                         .inspect_err(|_| tracing::warn!("invalid line: {}", line))?;
                     let mapfile = split[12];
-                    maps.push(MMap {
-                        file: strings.get_or_intern(mapfile),
-                        offset,
-                        addr: addr.0,
-                        len: addr.1,
+                    maps.push(MapEvent {
+                        pid: pids.0 as u32,
+                        time,
+                        change: MapChange::Map(MMap {
+                            file: strings.get_or_intern(mapfile),
+                            offset,
+                            addr: addr.0,
+                            len: addr.1,
+                        }),
                     })
                 }
             } else {
@@ -258,131 +445,310 @@ pub fn parse_perf_data<Io: Read>(
                     addr_sym,
                     addr,
                     ip,
+                    pid,
                     tid,
-                    time: Timestamp {
-                        sec: time.0,
-                        nsec: time.1,
-                    },
+                    cpu,
+                    time,
+                    write: None,
                 });
             }
         }
     }
 
-    let mut objects = StableVec::new();
-    let mut addrmap = nonoverlapping_interval_tree::NonOverlappingIntervalTree::new();
-    let mut objmap = HashMap::new();
-    tracing::info!("parsing {} maps", maps.len());
-    for map in maps {
-        tracing::debug!(
-            "map: {:?} {} {}",
-            strings.resolve(map.file),
-            map.addr,
-            map.len
-        );
-        let entry = objmap.entry(map.file).or_insert_with(|| {
-            let idx = objects.next_push_index();
-            objects.push(Object {
-                idx,
-                file: map.file,
-                maps: 0,
-                faults: 0,
-                biggest_offset: 0,
-                smallest_offset: u64::MAX,
-                show: true,
-            });
-            idx
-        });
-        addrmap.insert(map.addr..(map.addr + map.len), (*entry, map));
-    }
-
-    tracing::info!("parsing {} events", events.len());
-    let faults = events
-        .into_iter()
-        .filter_map(|event| {
-            let addr = event.addr & !0xfff;
-            if let Some(info) = addrmap.get(&addr) {
-                if addr != 0 {
-                    let map_offset = event.addr.checked_sub(info.1.addr).unwrap();
-                    let offset = map_offset + info.1.offset;
-                    strings.resolve(event.name).and_then(|event_name| {
-                        let kind = if event_name.starts_with("minor-faults") {
-                            Some(EventKind::MinorFault)
-                        } else if event_name.starts_with("major-faults") {
-                            Some(EventKind::MajorFault)
-                        } else if event_name.starts_with("cache-misses") {
-                            Some(EventKind::CacheMiss)
-                        } else {
-                            if let Some(kind) = ev_map.get(event_name) {
-                                Some(*kind)
-                            } else {
-                                Some(EventKind::Unknown)
-                            }
-                        };
-                        kind.map(|kind| Event {
-                            obj_idx: info.0,
-                            offset,
-                            was_write: false, //TODO
-                            time: event.time.into(),
-                            kind,
-                            event_name: event.name,
-                            addr: event.addr,
-                            ip: event.ip,
-                            tid: event.tid,
-                        })
-                    })
-                } else {
-                    None
-                }
-            } else {
-                //tracing::warn!("page-fault to untracked address {:x}", event.addr);
-                None
-            }
+    if !have_pids {
+        tracing::info!("events have no pids, treating all mappings as one process");
+        for map in &mut maps {
+            map.pid = 0;
+        }
+    }
+
+    resolve_events(events, maps, strings, &ev_map)
+}
+
+/// Convert mappings read from procfs into [`MapEvent`]s, which are in place from the start of the
+/// trace.
+pub fn intern_proc_maps(initial_maps: &[ProcMap], strings: &mut Interner) -> Vec<MapEvent> {
+    initial_maps
+        .iter()
+        .map(|map| MapEvent {
+            pid: map.pid,
+            time: Timestamp::from_nanos(0),
+            change: MapChange::Map(MMap {
+                file: strings.get_or_intern(&map.file),
+                offset: map.offset,
+                addr: map.addr,
+                len: map.len,
+            }),
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+/// Attribute raw perf events to the files mapped at their addresses, building the objects table.
+/// This is shared by every capture backend: they only differ in how they produce the raw events and
+/// mappings.
+pub fn resolve_events(
+    events: Vec<PerfEvent>,
+    maps: Vec<MapEvent>,
+    strings: Interner,
+    ev_map: &HashMap<&str, EventKind>,
+) -> Result<PerfData> {
+    tracing::info!("parsing {} events, {} maps", events.len(), maps.len());
+    let mut faults = Vec::with_capacity(events.len());
+    let mut resolver = Resolver::new(strings, ev_map);
+    resolver.resolve(events, maps, |fault| {
+        faults.push(fault);
+        Ok(())
+    })?;
+    let mut pd = resolver.finish();
+    pd.faults = faults;
+    Ok(pd)
+}
 
-    for fault in &faults {
-        objects[fault.obj_idx].faults += 1;
-        objects[fault.obj_idx].biggest_offset =
-            objects[fault.obj_idx].biggest_offset.max(fault.offset);
-        objects[fault.obj_idx].smallest_offset =
-            objects[fault.obj_idx].smallest_offset.min(fault.offset);
+/// Attributes raw perf events to the files mapped at their addresses as they come in, for
+/// backends that can't hold a whole capture in memory. Events and mappings have to be handed over
+/// in time order, at least from one call of [`Resolver::resolve`] to the next.
+pub struct Resolver<'a> {
+    ev_map: &'a HashMap<&'a str, EventKind>,
+    strings: Interner,
+    objects: StableVec<Object>,
+    objmap: HashMap<u32, usize>,
+    spaces: HashMap<u32, AddressSpace>,
+    /// The last page each thread faulted on, and whether it was a write. The page fault
+    /// tracepoint fires just before the fault events for the same access.
+    accesses: HashMap<u32, (u64, bool)>,
+    /// The code the events came from. The same instruction pointer can be different code in
+    /// different processes, or after an exec, so instructions are told apart by where they are
+    /// in the binary mapped at them, or by process when there's none.
+    sites: Vec<CodeSite>,
+    site_ids: HashMap<(Option<u32>, u64, Option<u32>), u32>,
+    /// Time of the first event. Times are reported relative to it, like `perf script --reltime`.
+    base: Option<Duration>,
+    events: usize,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(strings: Interner, ev_map: &'a HashMap<&'a str, EventKind>) -> Self {
+        Self {
+            ev_map,
+            strings,
+            objects: StableVec::new(),
+            objmap: HashMap::new(),
+            spaces: HashMap::new(),
+            accesses: HashMap::new(),
+            sites: Vec::new(),
+            site_ids: HashMap::new(),
+            base: None,
+            events: 0,
+        }
     }
 
-    // Filter objects
-    for idx in 0..objects.num_elements() {
-        let Some(object) = objects.get_mut(idx) else {
-            continue;
-        };
-        tracing::debug!(
-            "object: {} {}",
-            strings.resolve(object.file).unwrap_or("[unknown]"),
-            object.faults
-        );
-        if object.faults == 0 {
-            objects.remove(idx);
-            continue;
+    /// Resolve `events`, applying `maps` along the way, and pass each event that falls in a
+    /// mapped file to `emit`. Either may be out of order, as they are from backends that read
+    /// per-CPU buffers.
+    pub fn resolve(
+        &mut self,
+        mut events: Vec<PerfEvent>,
+        mut maps: Vec<MapEvent>,
+        mut emit: impl FnMut(Event) -> Result<()>,
+    ) -> Result<()> {
+        events.sort_by_key(|event| Duration::from(event.time));
+        maps.sort_by_key(|map| Duration::from(map.time));
+        let mut maps = maps.into_iter().peekable();
+        for event in events {
+            while let Some(map) = maps.next_if(|map| Duration::from(map.time) <= event.time.into())
+            {
+                self.map(map);
+            }
+            if let Some(fault) = self.event(event)? {
+                emit(fault)?;
+            }
         }
-        if object.biggest_offset == 0 {
-            object.biggest_offset = PAGE_SIZE;
+        maps.for_each(|map| self.map(map));
+        Ok(())
+    }
+
+    fn map(&mut self, map: MapEvent) {
+        match map.change {
+            MapChange::Map(mmap) => {
+                tracing::debug!(
+                    "map: {} {:?} {} {}",
+                    map.pid,
+                    self.strings.resolve(mmap.file),
+                    mmap.addr,
+                    mmap.len
+                );
+                if mmap.len == 0 {
+                    return;
+                }
+                let objects = &mut self.objects;
+                let idx = *self.objmap.entry(mmap.file).or_insert_with(|| {
+                    let idx = objects.next_push_index();
+                    objects.push(Object {
+                        idx,
+                        file: mmap.file,
+                        maps: 0,
+                        faults: 0,
+                        biggest_offset: 0,
+                        smallest_offset: u64::MAX,
+                        show: true,
+                        size: 0,
+                    });
+                    idx
+                });
+                objects[idx].maps += 1;
+                objects[idx].size = objects[idx].size.max(mmap.len);
+                self.spaces.entry(map.pid).or_default().map(idx, mmap);
+            }
+            MapChange::Unmap { addr, len } => {
+                if let Some(space) = self.spaces.get_mut(&map.pid) {
+                    space.unmap(addr..addr.saturating_add(len));
+                }
+            }
+            MapChange::Fork { parent } => {
+                let space = self.spaces.get(&parent).cloned().unwrap_or_default();
+                self.spaces.insert(map.pid, space);
+            }
+            MapChange::Exec => {
+                self.spaces.remove(&map.pid);
+            }
         }
-        object.biggest_offset = object.biggest_offset.next_multiple_of(PAGE_SIZE);
-        object.smallest_offset = object
-            .smallest_offset
-            .next_multiple_of(PAGE_SIZE)
-            .saturating_sub(PAGE_SIZE);
     }
 
-    tracing::info!(
-        "parsing complete: {} events, {} objects",
-        faults.len(),
-        objects.num_elements()
-    );
+    fn event(&mut self, event: PerfEvent) -> Result<Option<Event>> {
+        let base = *self.base.get_or_insert(event.time.into());
+        let strings = &mut self.strings;
+        if let Some(write) = event.write
+            && strings.resolve(event.name) == Some(perf_sys::PAGE_FAULT_TRACEPOINT)
+        {
+            self.accesses
+                .insert(event.tid, (event.addr & !0xfff, write));
+            return Ok(None);
+        }
 
-    Ok(PerfData {
-        faults,
-        objects,
-        strings,
-    })
+        let addr = event.addr & !0xfff;
+        if addr == 0 {
+            return Ok(None);
+        }
+        let Some(space) = self.spaces.get(&event.pid) else {
+            return Ok(None);
+        };
+        let Some(info) = space.get(addr) else {
+            //tracing::warn!("page-fault to untracked address {:x}", event.addr);
+            return Ok(None);
+        };
+        let code = (event.ip != 0).then(|| {
+            let mapped = space
+                .get(event.ip)
+                .map(|(_, map)| (map.file, event.ip - map.addr + map.offset));
+            let key = match mapped {
+                Some((binary, offset)) => (Some(binary), offset, None),
+                None => (None, event.ip, Some(event.pid)),
+            };
+            *self.site_ids.entry(key).or_insert_with(|| {
+                let symbol = (strings.resolve(event.sym) != Some("[unknown]")).then_some(event.sym);
+                self.sites.push(CodeSite {
+                    ip: event.ip,
+                    mapped,
+                    symbol,
+                });
+                self.sites.len() as u32 - 1
+            })
+        });
+        let Some(map_offset) = event.addr.checked_sub(info.1.addr) else {
+            bail!(
+                "address {:#x} is below the mapping at {:#x} it was found in",
+                event.addr,
+                info.1.addr
+            );
+        };
+        let offset = map_offset + info.1.offset;
+        let Some(event_name) = strings.resolve(event.name) else {
+            return Ok(None);
+        };
+        let kind = if event_name.starts_with("minor-faults") {
+            EventKind::MinorFault
+        } else if event_name.starts_with("major-faults") {
+            EventKind::MajorFault
+        } else if event_name.starts_with("cache-misses") {
+            EventKind::CacheMiss
+        } else if let Some(kind) = self.ev_map.get(event_name) {
+            *kind
+        } else {
+            EventKind::Unknown
+        };
+
+        let object = &mut self.objects[info.0];
+        object.faults += 1;
+        object.biggest_offset = object.biggest_offset.max(offset);
+        object.smallest_offset = object.smallest_offset.min(offset);
+        self.events += 1;
+        Ok(Some(Event {
+            obj_idx: info.0,
+            offset,
+            was_write: event.write.or_else(|| {
+                self.accesses
+                    .get(&event.tid)
+                    .filter(|(page, _)| *page == addr)
+                    .map(|(_, write)| *write)
+            }),
+            time: Duration::from(event.time).saturating_sub(base),
+            kind,
+            event_name: event.name,
+            addr: event.addr,
+            ip: event.ip,
+            code,
+            tid: event.tid,
+            cpu: event.cpu,
+        }))
+    }
+
+    /// The string table, for interning the names of events and files still to come.
+    pub fn strings(&mut self) -> &mut Interner {
+        &mut self.strings
+    }
+
+    /// Drop the objects that no event fell in and symbolize the code. The returned data has no
+    /// events of its own; they were passed on as they were resolved.
+    pub fn finish(mut self) -> PerfData {
+        let objects = &mut self.objects;
+        for idx in 0..objects.num_elements() {
+            let Some(object) = objects.get_mut(idx) else {
+                continue;
+            };
+            tracing::debug!(
+                "object: {} {}",
+                self.strings.resolve(object.file).unwrap_or("[unknown]"),
+                object.faults
+            );
+            if object.faults == 0 {
+                objects.remove(idx);
+                continue;
+            }
+            if object.biggest_offset == 0 {
+                object.biggest_offset = PAGE_SIZE;
+            }
+            object.biggest_offset = object.biggest_offset.next_multiple_of(PAGE_SIZE);
+            object.smallest_offset = object
+                .smallest_offset
+                .next_multiple_of(PAGE_SIZE)
+                .saturating_sub(PAGE_SIZE);
+        }
+
+        tracing::info!(
+            "parsing complete: {} events, {} objects",
+            self.events,
+            self.objects.num_elements()
+        );
+        let code = symbols::resolve(&self.sites, &mut self.strings);
+
+        PerfData {
+            faults: Vec::new(),
+            spilled: None,
+            objects: self.objects,
+            strings: self.strings,
+            code,
+        }
+    }
 }
 
 #[repr(C)]
@@ -392,7 +758,10 @@ pub struct EventRecord {
     ip: u64,
     offset: u64,
     time_ns: u64,
-    _resv: u64,
+    /// One more than the index of the instruction's location in [`JsonRoot::code`], or zero if
+    /// it's unknown, as it is in older traces.
+    code: u32,
+    _resv: u32,
     kind: u32,
     flags: u32,
     event_name: u32,
@@ -401,6 +770,11 @@ pub struct EventRecord {
     cpu: u32,
 }
 
+/// [`EventRecord`] flag: whether the access was a read or write is known.
+pub const RECORD_FLAG_ACCESS_KNOWN: u32 = 1 << 0;
+/// [`EventRecord`] flag: the access was a write.
+pub const RECORD_FLAG_WRITE: u32 = 1 << 1;
+
 impl EventRecord {
     pub fn time(&self) -> Duration {
         Duration::from_nanos(self.time_ns)
@@ -417,6 +791,38 @@ impl EventRecord {
     pub fn obj_id(&self) -> usize {
         self.obj_id as usize
     }
+
+    pub fn ip(&self) -> u64 {
+        self.ip
+    }
+
+    /// Index of the instruction's location in [`JsonRoot::code`], if it's known.
+    pub fn code(&self) -> Option<usize> {
+        (self.code as usize).checked_sub(1)
+    }
+
+    /// The faulting address, before it was resolved to an offset.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// The interned name of the perf event that recorded this.
+    pub fn event_name(&self) -> u32 {
+        self.event_name
+    }
+
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn cpu(&self) -> u32 {
+        self.cpu
+    }
+
+    /// Whether the access was a write, if known.
+    pub fn was_write(&self) -> Option<bool> {
+        (self.flags & RECORD_FLAG_ACCESS_KNOWN != 0).then_some(self.flags & RECORD_FLAG_WRITE != 0)
+    }
 }
 
 #[repr(C)]
@@ -424,16 +830,34 @@ impl EventRecord {
 pub struct RecordHeader {
     magic: u64,
     count: u64,
-    _resv: [u64; 6],
+    /// Zero in files written before the header was versioned, which are otherwise version 1.
+    version: u32,
+    /// Size of each record, zero in unversioned files.
+    record_size: u32,
+    _resv: [u64; 5],
 }
 
+pub const RECORDS_MAGIC: u64 = 0xAAAA1111CAFED00D;
+pub const RECORDS_VERSION: u32 = 1;
+
 impl RecordHeader {
     pub fn record_count(&self) -> usize {
         self.count as usize
     }
 
     pub fn is_valid(&self) -> bool {
-        self.magic == 0xAAAA1111CAFED00D
+        self.magic == RECORDS_MAGIC
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version.max(1)
+    }
+
+    pub fn record_size(&self) -> usize {
+        match self.record_size {
+            0 => size_of::<EventRecord>(),
+            size => size as usize,
+        }
     }
 }
 
@@ -441,77 +865,184 @@ impl RecordHeader {
 pub struct JsonRoot {
     pub objects: HashMap<usize, Object>,
     pub strings: Interner,
+    /// Where the events came from in the code (empty in older traces).
+    #[serde(default)]
+    pub code: Vec<CodeLocation>,
+}
+
+/// [`EventRecord`]s written to a scratch file as they were captured.
+#[derive(Debug)]
+pub struct SpilledRecords {
+    out: BufWriter<File>,
+    count: usize,
 }
 
-pub fn write_perf_data<W: Write, WJ: Write>(
-    pd: &PerfData,
-    mut out: BufWriter<W>,
-    out_json: BufWriter<WJ>,
-) -> Result<()> {
-    out.write(bytemuck::bytes_of(&RecordHeader {
-        magic: 0xAAAA1111CAFED00D,
-        count: pd.faults.len() as u64,
-        _resv: [0; 6],
+impl SpilledRecords {
+    /// Write records into `file`, which should be empty.
+    pub fn new(file: File) -> Self {
+        Self {
+            out: BufWriter::new(file),
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, event: &Event) -> Result<()> {
+        self.out
+            .write_all(bytemuck::bytes_of(&EventRecord::from(event)))?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// How many records have been written.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Make sure every record is in the file, before it's read back.
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Write the records file: a [`RecordHeader`] followed by one [`EventRecord`] per event.
+pub fn write_records<W: Write>(pd: &PerfData, out: &mut W) -> Result<()> {
+    let count = pd.spilled.as_ref().map_or(0, |spilled| spilled.count) + pd.faults.len();
+    out.write_all(bytemuck::bytes_of(&RecordHeader {
+        magic: RECORDS_MAGIC,
+        count: count as u64,
+        version: RECORDS_VERSION,
+        record_size: size_of::<EventRecord>() as u32,
+        _resv: [0; 5],
     }))?;
+    if let Some(spilled) = &pd.spilled {
+        let mut file = spilled.out.get_ref();
+        file.seek(SeekFrom::Start(0))?;
+        let copied = std::io::copy(&mut file, out)?;
+        if copied != (spilled.count * size_of::<EventRecord>()) as u64 {
+            bail!("the scratch file is missing some of the captured records");
+        }
+    }
     for ev in &pd.faults {
-        let record = EventRecord {
+        out.write_all(bytemuck::bytes_of(&EventRecord::from(ev)))?;
+    }
+    Ok(())
+}
+
+impl From<&Event> for EventRecord {
+    fn from(ev: &Event) -> Self {
+        EventRecord {
             addr: ev.addr,
             ip: ev.ip,
             offset: ev.offset,
             time_ns: ev.time.as_nanos() as u64,
             kind: ev.kind.into(),
-            flags: 0,
+            flags: match ev.was_write {
+                Some(true) => RECORD_FLAG_ACCESS_KNOWN | RECORD_FLAG_WRITE,
+                Some(false) => RECORD_FLAG_ACCESS_KNOWN,
+                None => 0,
+            },
             event_name: ev.event_name,
             obj_id: ev.obj_idx as u32,
             tid: ev.tid,
-            cpu: 0,
+            cpu: ev.cpu,
+            code: ev.code.map_or(0, |code| code + 1),
             _resv: 0,
-        };
-
-        out.write(bytemuck::bytes_of(&record))?;
+        }
     }
-    out.flush()?;
-
-    let root = JsonRoot {
-        strings: pd.strings.clone(),
-        objects: pd.objects.iter().map(|x| (x.0, x.1.clone())).collect(),
-    };
-
-    serde_json::to_writer(out_json, &root)?;
-    Ok(())
 }
 
 #[derive(Debug)]
 pub struct Records {
     map: Mmap,
+    /// Where the records file starts within the mapping.
+    start: usize,
+    /// Number of complete records, which is less than the header's count if the file was
+    /// truncated.
+    count: usize,
 }
 
 impl Records {
+    /// Check the records file at `start..start + len` in `map`, so that [`Records::slice`] only
+    /// covers complete, aligned records.
+    pub fn new(map: Mmap, start: usize, len: usize) -> Result<Self> {
+        let Some(bytes) = map.get(start..start.saturating_add(len)) else {
+            bail!("records extend past end of file");
+        };
+        let Some(header) = bytes.get(..size_of::<RecordHeader>()) else {
+            bail!("records file is too short to hold its header");
+        };
+        let header: RecordHeader = bytemuck::pod_read_unaligned(header);
+        if !header.is_valid() {
+            bail!("invalid header in records file");
+        }
+        if header.version() > RECORDS_VERSION {
+            bail!(
+                "records file is version {}, but this pfviz only supports up to version {}",
+                header.version(),
+                RECORDS_VERSION
+            );
+        }
+        if header.record_size() != size_of::<EventRecord>() {
+            bail!(
+                "records file has {} byte records, expected {}",
+                header.record_size(),
+                size_of::<EventRecord>()
+            );
+        }
+        let first = bytes[size_of::<RecordHeader>()..].as_ptr();
+        if first.align_offset(align_of::<EventRecord>()) != 0 {
+            bail!("records are misaligned in the file");
+        }
+
+        let complete = (len - size_of::<RecordHeader>()) / size_of::<EventRecord>();
+        let count = header.record_count();
+        if count > complete {
+            tracing::warn!(
+                "records file is truncated: header lists {} records but only {} are complete, \
+                 playing those",
+                count,
+                complete
+            );
+        }
+        Ok(Records {
+            count: count.min(complete),
+            map,
+            start,
+        })
+    }
+
     fn record_start(&self) -> *const EventRecord {
         unsafe {
             self.map
                 .as_ptr()
-                .add(size_of::<RecordHeader>())
+                .add(self.start + size_of::<RecordHeader>())
                 .cast::<EventRecord>()
         }
     }
 
     pub fn header(&self) -> &RecordHeader {
-        unsafe { self.map.as_ptr().cast::<RecordHeader>().as_ref().unwrap() }
+        unsafe {
+            self.map
+                .as_ptr()
+                .add(self.start)
+                .cast::<RecordHeader>()
+                .as_ref()
+                .unwrap()
+        }
     }
 
     pub fn slice(&self) -> &[EventRecord] {
-        unsafe { core::slice::from_raw_parts(self.record_start(), self.header().record_count()) }
+        // Safety: new() checked that count complete, aligned records follow the header.
+        unsafe { core::slice::from_raw_parts(self.record_start(), self.count) }
     }
 }
 
 pub fn mmap_records<P: AsRef<Path>>(path: P) -> Result<Records> {
     let file = File::open(path)?;
-    let recs = unsafe { memmap2::Mmap::map(&file) }.map(|map| Records { map })?;
-    if !recs.header().is_valid() {
-        bail!("invalid header in records file");
-    }
-    Ok(recs)
+    let map = unsafe { memmap2::Mmap::map(&file) }?;
+    let len = map.len();
+    Records::new(map, 0, len)
 }
 
 pub fn open_json_root<P: AsRef<Path>>(path: P) -> Result<JsonRoot> {
@@ -520,17 +1051,56 @@ pub fn open_json_root<P: AsRef<Path>>(path: P) -> Result<JsonRoot> {
     Ok(root)
 }
 
+/// Paths of the records (.dat) and metadata (.json) files that made up a trace before the
+/// single-file format. `path` may be the trace's base name or either of its files.
+pub fn trace_paths<P: AsRef<Path>>(path: P) -> (PathBuf, PathBuf) {
+    let path = path.as_ref();
+    let base = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("dat" | "json") => path.with_extension(""),
+        _ => path.to_path_buf(),
+    };
+    let with_suffix = |suffix: &str| {
+        let mut name = base.clone().into_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    (with_suffix(".dat"), with_suffix(".json"))
+}
+
 #[derive(Debug)]
 pub struct FaultData {
     pub json: JsonRoot,
     pub records: Records,
+    pub meta: CaptureMeta,
 }
 
 impl FaultData {
-    pub fn open<P: AsRef<Path>, P2: AsRef<Path>>(data: P, json: P2) -> Result<Self> {
+    /// Open a trace, either a single-file trace (with or without its extension), or the base name
+    /// or one of the files of a .dat and .json pair.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if container::is_container(path) {
+            return container::read_trace(path);
+        }
+        let container_path = container::trace_path(path);
+        if container_path.exists() {
+            return container::read_trace(container_path);
+        }
+
+        let (data, json) = trace_paths(path);
+        if !data.exists() || !json.exists() {
+            bail!(
+                "no trace found at {} (looked for {}, and for {} with {})",
+                path.display(),
+                container_path.display(),
+                data.display(),
+                json.display()
+            );
+        }
         Ok(Self {
             json: open_json_root(json)?,
             records: mmap_records(data)?,
+            meta: CaptureMeta::default(),
         })
     }
 
@@ -542,4 +1112,13 @@ impl FaultData {
         let obj = self.object(fault);
         self.json.strings.resolve(obj.file).unwrap_or("[unknown]")
     }
+
+    /// Where the instruction that caused `fault` is, for display.
+    pub fn code_location(&self, fault: &EventRecord) -> ShowLocation<'_> {
+        ShowLocation {
+            ip: fault.ip(),
+            location: fault.code().and_then(|code| self.json.code.get(code)),
+            strings: &self.json.strings,
+        }
+    }
 }
@@ -0,0 +1,362 @@
+//! Reader for the perf.data file format written by `perf record`, so that traces can be imported
+//! without going through `perf script`.
+//!
+//! See `tools/perf/Documentation/perf.data-file-format.txt` in the kernel tree.
+
+use std::{collections::HashMap, fs::File, path::Path};
+
+use color_eyre::eyre::{OptionExt, Result, bail};
+
+use crate::{
+    perf::{
+        self, EventKind, Interner, MMap, MapChange, MapEvent, PerfData, PerfEvent, ProcMap,
+        Timestamp,
+    },
+    perf_sys::{self, Cursor},
+};
+
+pub const MAGIC: &[u8; 8] = b"PERFILE2";
+
+/// Feature bit for the section that names each event.
+const HEADER_EVENT_DESC: usize = 12;
+
+/// `struct perf_file_section`.
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    offset: u64,
+    size: u64,
+}
+
+impl Section {
+    fn read(cur: &mut Cursor) -> Option<Self> {
+        Some(Self {
+            offset: cur.u64()?,
+            size: cur.u64()?,
+        })
+    }
+
+    fn slice<'a>(&self, file: &'a [u8]) -> Result<&'a [u8]> {
+        self.offset
+            .checked_add(self.size)
+            .and_then(|end| file.get(self.offset as usize..end as usize))
+            .ok_or_eyre("perf.data section extends past end of file")
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attr {
+    kind: u32,
+    config: u64,
+    sample_type: u64,
+    flags: u64,
+    ids: Vec<u64>,
+    name: Option<String>,
+}
+
+impl Attr {
+    /// Name for events that aren't described by the file, following perf's naming.
+    fn default_name(&self) -> String {
+        match (self.kind, self.config) {
+            (perf_sys::PERF_TYPE_SOFTWARE, perf_sys::PERF_COUNT_SW_PAGE_FAULTS_MIN) => {
+                "minor-faults".into()
+            }
+            (perf_sys::PERF_TYPE_SOFTWARE, perf_sys::PERF_COUNT_SW_PAGE_FAULTS_MAJ) => {
+                "major-faults".into()
+            }
+            (perf_sys::PERF_TYPE_HARDWARE, 3) => "cache-misses".into(),
+            (kind, config) => format!("type{}:{:#x}", kind, config),
+        }
+    }
+}
+
+/// Read the attr table and the ids associated with each attr.
+fn read_attrs(file: &[u8], attrs: Section, attr_size: u64) -> Result<Vec<Attr>> {
+    let table = attrs.slice(file)?;
+    let mut out = Vec::new();
+    for entry in table.chunks_exact(attr_size as usize) {
+        let (attr, ids) = entry.split_at(attr_size as usize - 16);
+        let mut cur = Cursor::new(attr);
+        let kind = cur.u32().ok_or_eyre("truncated attr")?;
+        cur.skip(4);
+        let config = cur.u64().ok_or_eyre("truncated attr")?;
+        cur.skip(8);
+        let sample_type = cur.u64().ok_or_eyre("truncated attr")?;
+        cur.skip(8);
+        let flags = cur.u64().ok_or_eyre("truncated attr")?;
+        let ids = Section::read(&mut Cursor::new(ids))
+            .ok_or_eyre("truncated attr")?
+            .slice(file)?
+            .chunks_exact(8)
+            .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+        out.push(Attr {
+            kind,
+            config,
+            sample_type,
+            flags,
+            ids,
+            name: None,
+        });
+    }
+    Ok(out)
+}
+
+/// Fill in event names from the `HEADER_EVENT_DESC` feature section, if present.
+fn read_event_desc(file: &[u8], data: Section, features: &[u64; 4], attrs: &mut [Attr]) {
+    let has = |bit: usize| features[bit / 64] & (1 << (bit % 64)) != 0;
+    if !has(HEADER_EVENT_DESC) {
+        return;
+    }
+    // Feature sections are listed after the data, one per set bit, in bit order.
+    let index = (0..HEADER_EVENT_DESC).filter(|bit| has(*bit)).count() as u64;
+    let table_off = (data.offset + data.size + index * 16) as usize;
+    let Some(section) = file
+        .get(table_off..)
+        .and_then(|t| Section::read(&mut Cursor::new(t)))
+    else {
+        return;
+    };
+    let Ok(desc) = section.slice(file) else {
+        return;
+    };
+
+    let mut cur = Cursor::new(desc);
+    let (Some(nr), Some(attr_size)) = (cur.u32(), cur.u32()) else {
+        return;
+    };
+    for i in 0..nr as usize {
+        let parsed = (|| {
+            cur.skip(attr_size as usize)?;
+            let nr_ids = cur.u32()?;
+            let len = cur.u32()?;
+            let name = cur.bytes(len as usize)?;
+            cur.skip(nr_ids as usize * 8)?;
+            let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            Some(String::from_utf8_lossy(&name[..end]).into_owned())
+        })();
+        match (parsed, attrs.get_mut(i)) {
+            (Some(name), Some(attr)) => attr.name = Some(name),
+            _ => return,
+        }
+    }
+}
+
+/// Check whether a file starts with the perf.data magic.
+pub fn is_perf_data<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic))
+        .is_ok_and(|_| &magic == MAGIC)
+}
+
+/// Decode a perf.data file into the same [`PerfData`] that [`perf::parse_perf_data`] produces
+/// from `perf script` output, except that perf's symbol names for the instruction and data
+/// addresses aren't resolved. Instructions are symbolized from the mapped binaries instead (see
+/// [`crate::symbols`]), and data addresses are only placed in the files mapped there.
+pub fn read_perf_data<P: AsRef<Path>>(
+    path: P,
+    ev_map: &HashMap<&str, EventKind>,
+    initial_maps: &[ProcMap],
+) -> Result<PerfData> {
+    let file = File::open(path)?;
+    let map = unsafe { memmap2::Mmap::map(&file) }?;
+    let file = &map[..];
+
+    let mut cur = Cursor::new(file);
+    match cur.bytes(8) {
+        Some(magic) if magic == MAGIC => {}
+        Some(b"2ELIFREP") => bail!("big-endian perf.data files are not supported"),
+        _ => bail!("not a perf.data file (or written in pipe mode)"),
+    }
+    let truncated = || color_eyre::eyre::eyre!("truncated perf.data header");
+    let _header_size = cur.u64().ok_or_else(truncated)?;
+    let attr_size = cur.u64().ok_or_else(truncated)?;
+    let attrs_section = Section::read(&mut cur).ok_or_else(truncated)?;
+    let data = Section::read(&mut cur).ok_or_else(truncated)?;
+    let _event_types = Section::read(&mut cur).ok_or_else(truncated)?;
+    let mut features = [0u64; 4];
+    for word in &mut features {
+        *word = cur.u64().ok_or_else(truncated)?;
+    }
+    if attr_size <= 16 {
+        bail!("invalid attr size in perf.data header");
+    }
+
+    let mut attrs = read_attrs(file, attrs_section, attr_size)?;
+    if attrs.is_empty() {
+        bail!("perf.data file has no events");
+    }
+    read_event_desc(file, data, &features, &mut attrs);
+
+    // Samples can only be decoded if we can find out which event they belong to before knowing
+    // their layout, which is what PERF_SAMPLE_IDENTIFIER is for. Without it, all events must
+    // share one layout.
+    let identified = attrs
+        .iter()
+        .all(|a| a.sample_type & perf_sys::PERF_SAMPLE_IDENTIFIER != 0);
+    let sample_type = attrs[0].sample_type;
+    if !identified && attrs.iter().any(|a| a.sample_type != sample_type) {
+        bail!("perf.data events have differing sample layouts, which is not supported");
+    }
+
+    let mut strings = Interner::default();
+    let unknown = strings.get_or_intern("[unknown]");
+    let names = attrs
+        .iter()
+        .map(|attr| {
+            let name = attr.name.clone().unwrap_or_else(|| attr.default_name());
+            strings.get_or_intern(&name)
+        })
+        .collect::<Vec<_>>();
+    let mut ids = HashMap::new();
+    for (attr, name) in attrs.iter().zip(&names) {
+        for id in &attr.ids {
+            ids.insert(*id, (*name, attr.sample_type));
+        }
+    }
+    let munmap = strings.get_or_intern(perf_sys::MUNMAP_TRACEPOINT);
+    let page_fault = strings.get_or_intern(perf_sys::PAGE_FAULT_TRACEPOINT);
+    // Layout of the sample_id fields on non-sample records, which give them a time. Without
+    // sample_id_all they have none, and are treated as having happened at the start.
+    let sample_id_type = |body: &[u8]| {
+        let sample_type = if identified {
+            ids.get(&perf_sys::record_identifier(body)?)?.1
+        } else {
+            sample_type
+        };
+        (attrs[0].flags & perf_sys::ATTR_FLAG_SAMPLE_ID_ALL != 0).then_some(sample_type)
+    };
+    let record_time = |body: &[u8]| {
+        sample_id_type(body)
+            .and_then(|sample_type| perf_sys::parse_sample_id(body, sample_type))
+            .map_or(0, |id| id.time)
+    };
+
+    let mut events = Vec::new();
+    let mut maps = perf::intern_proc_maps(initial_maps, &mut strings);
+    let mut lost = 0u64;
+    let mut records = data.slice(file)?;
+    tracing::info!("Reading from perf.data");
+    while records.len() >= size_of::<perf_sys::RecordHeader>() {
+        let header: perf_sys::RecordHeader =
+            bytemuck::pod_read_unaligned(&records[..size_of::<perf_sys::RecordHeader>()]);
+        let size = header.size as usize;
+        if size < size_of::<perf_sys::RecordHeader>() || size > records.len() {
+            tracing::warn!("truncated record in perf.data, ignoring the rest");
+            break;
+        }
+        let body = &records[size_of::<perf_sys::RecordHeader>()..size];
+        records = &records[size..];
+
+        match header.kind {
+            perf_sys::PERF_RECORD_SAMPLE => {
+                let sample_type = if identified {
+                    match Cursor::new(body).u64().and_then(|id| ids.get(&id)) {
+                        Some((_, sample_type)) => *sample_type,
+                        None => continue,
+                    }
+                } else {
+                    sample_type
+                };
+                let Some(sample) = perf_sys::parse_sample(body, sample_type) else {
+                    continue;
+                };
+                let name = match sample.id {
+                    Some(id) => match ids.get(&id) {
+                        Some((name, _)) => *name,
+                        None => continue,
+                    },
+                    None => names[0],
+                };
+                if name == munmap {
+                    if let Some((addr, len)) = perf_sys::parse_munmap(sample.raw) {
+                        maps.push(MapEvent {
+                            pid: sample.pid,
+                            time: Timestamp::from_nanos(sample.time),
+                            change: MapChange::Unmap { addr, len },
+                        });
+                    }
+                    continue;
+                }
+                let (addr, write) = if name == page_fault {
+                    match perf_sys::parse_page_fault(sample.raw) {
+                        Some((addr, error_code)) => {
+                            (addr, Some(error_code & perf_sys::PF_WRITE != 0))
+                        }
+                        None => continue,
+                    }
+                } else {
+                    (sample.addr, None)
+                };
+                if sample.tid == 0 || addr == 0 {
+                    continue;
+                }
+                events.push(PerfEvent {
+                    name,
+                    sym: unknown,
+                    addr_sym: unknown,
+                    addr,
+                    ip: sample.ip,
+                    pid: sample.pid,
+                    tid: sample.tid,
+                    cpu: sample.cpu,
+                    write,
+                    time: Timestamp::from_nanos(sample.time),
+                });
+                if events.len() % 100_000 == 0 {
+                    tracing::debug!("read {} events so far", events.len());
+                }
+            }
+            perf_sys::PERF_RECORD_MMAP | perf_sys::PERF_RECORD_MMAP2 => {
+                if let Some(map) = perf_sys::parse_mmap(header.kind, body)
+                    && map.pid != 0
+                    && map.pid != u32::MAX
+                {
+                    maps.push(MapEvent {
+                        pid: map.pid,
+                        time: Timestamp::from_nanos(record_time(body)),
+                        change: MapChange::Map(MMap {
+                            file: strings.get_or_intern(map.filename),
+                            offset: map.pgoff,
+                            addr: map.addr,
+                            len: map.len,
+                        }),
+                    });
+                }
+            }
+            perf_sys::PERF_RECORD_FORK => {
+                if let Some(fork) = perf_sys::parse_fork(body)
+                    && fork.pid != fork.ppid
+                {
+                    maps.push(MapEvent {
+                        pid: fork.pid,
+                        time: Timestamp::from_nanos(fork.time),
+                        change: MapChange::Fork { parent: fork.ppid },
+                    });
+                }
+            }
+            perf_sys::PERF_RECORD_COMM
+                if header.misc & perf_sys::PERF_RECORD_MISC_COMM_EXEC != 0 =>
+            {
+                if let Some(pid) = Cursor::new(body).u32() {
+                    maps.push(MapEvent {
+                        pid,
+                        time: Timestamp::from_nanos(record_time(body)),
+                        change: MapChange::Exec,
+                    });
+                }
+            }
+            perf_sys::PERF_RECORD_LOST => {
+                let mut cur = Cursor::new(body);
+                cur.skip(8);
+                lost += cur.u64().unwrap_or(0);
+            }
+            _ => {}
+        }
+    }
+    if lost > 0 {
+        tracing::warn!("perf.data reports {} lost records", lost);
+    }
+
+    perf::resolve_events(events, maps, strings, ev_map)
+}
@@ -0,0 +1,320 @@
+//! Definitions from the kernel's perf_event ABI (`include/uapi/linux/perf_event.h`), and decoding
+//! of the records it produces.
+
+pub const PERF_TYPE_HARDWARE: u32 = 0;
+pub const PERF_TYPE_SOFTWARE: u32 = 1;
+pub const PERF_TYPE_TRACEPOINT: u32 = 2;
+pub const PERF_TYPE_RAW: u32 = 4;
+
+pub const PERF_COUNT_SW_PAGE_FAULTS_MIN: u64 = 5;
+pub const PERF_COUNT_SW_PAGE_FAULTS_MAJ: u64 = 6;
+
+pub const PERF_SAMPLE_IP: u64 = 1 << 0;
+pub const PERF_SAMPLE_TID: u64 = 1 << 1;
+pub const PERF_SAMPLE_TIME: u64 = 1 << 2;
+pub const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+pub const PERF_SAMPLE_READ: u64 = 1 << 4;
+pub const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+pub const PERF_SAMPLE_ID: u64 = 1 << 6;
+pub const PERF_SAMPLE_CPU: u64 = 1 << 7;
+pub const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+pub const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+pub const PERF_SAMPLE_RAW: u64 = 1 << 10;
+pub const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+
+pub const ATTR_FLAG_DISABLED: u64 = 1 << 0;
+pub const ATTR_FLAG_INHERIT: u64 = 1 << 1;
+pub const ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+pub const ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+pub const ATTR_FLAG_MMAP: u64 = 1 << 8;
+pub const ATTR_FLAG_COMM: u64 = 1 << 9;
+pub const ATTR_FLAG_ENABLE_ON_EXEC: u64 = 1 << 12;
+pub const ATTR_FLAG_TASK: u64 = 1 << 13;
+pub const ATTR_FLAG_WATERMARK: u64 = 1 << 14;
+pub const ATTR_PRECISE_IP_SHIFT: u64 = 15;
+pub const ATTR_FLAG_MMAP_DATA: u64 = 1 << 17;
+pub const ATTR_FLAG_SAMPLE_ID_ALL: u64 = 1 << 18;
+pub const ATTR_FLAG_MMAP2: u64 = 1 << 23;
+pub const ATTR_FLAG_COMM_EXEC: u64 = 1 << 24;
+
+pub const PERF_RECORD_MMAP: u32 = 1;
+pub const PERF_RECORD_LOST: u32 = 2;
+pub const PERF_RECORD_COMM: u32 = 3;
+pub const PERF_RECORD_FORK: u32 = 7;
+pub const PERF_RECORD_SAMPLE: u32 = 9;
+pub const PERF_RECORD_MMAP2: u32 = 10;
+
+/// Set in the header of a `PERF_RECORD_COMM` that was caused by exec.
+pub const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13;
+
+pub const PERF_FLAG_FD_CLOEXEC: u64 = 1 << 3;
+
+pub const PERF_EVENT_IOC_SET_OUTPUT: u64 = 0x2405;
+pub const PERF_EVENT_IOC_ID: u64 = 0x8008_2407;
+
+/// `struct perf_event_attr`, as of `PERF_ATTR_SIZE_VER5`. The bitfield word is kept as a plain
+/// integer, see the `ATTR_FLAG_*` constants.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PerfEventAttr {
+    pub kind: u32,
+    pub size: u32,
+    pub config: u64,
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_watermark: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+    pub config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub _resv: u16,
+}
+
+/// Offsets into `struct perf_event_mmap_page`, the control page at the start of a ring buffer.
+pub const MMAP_PAGE_DATA_HEAD: usize = 1024;
+pub const MMAP_PAGE_DATA_TAIL: usize = 1032;
+
+/// `struct perf_event_header`, at the start of every record.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RecordHeader {
+    pub kind: u32,
+    pub misc: u16,
+    pub size: u16,
+}
+
+/// Little-endian reader over the body of a record.
+pub struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    /// A NUL-terminated string padded out to the end of the record.
+    pub fn string(&mut self) -> Option<&'a str> {
+        let rest = self.bytes(self.buf.len() - self.pos)?;
+        let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        std::str::from_utf8(&rest[..end]).ok()
+    }
+}
+
+/// The fields of a `PERF_RECORD_SAMPLE` that we use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample<'a> {
+    pub id: Option<u64>,
+    pub ip: u64,
+    pub pid: u32,
+    pub tid: u32,
+    pub time: u64,
+    pub addr: u64,
+    pub cpu: u32,
+    /// Tracepoint data, empty unless `PERF_SAMPLE_RAW` was requested.
+    pub raw: &'a [u8],
+}
+
+/// Decode the body (without the header) of a `PERF_RECORD_SAMPLE` generated with `sample_type`.
+pub fn parse_sample(body: &[u8], sample_type: u64) -> Option<Sample<'_>> {
+    let mut cur = Cursor::new(body);
+    let mut sample = Sample::default();
+    if sample_type & PERF_SAMPLE_IDENTIFIER != 0 {
+        sample.id = Some(cur.u64()?);
+    }
+    if sample_type & PERF_SAMPLE_IP != 0 {
+        sample.ip = cur.u64()?;
+    }
+    if sample_type & PERF_SAMPLE_TID != 0 {
+        sample.pid = cur.u32()?;
+        sample.tid = cur.u32()?;
+    }
+    if sample_type & PERF_SAMPLE_TIME != 0 {
+        sample.time = cur.u64()?;
+    }
+    if sample_type & PERF_SAMPLE_ADDR != 0 {
+        sample.addr = cur.u64()?;
+    }
+    if sample_type & PERF_SAMPLE_ID != 0 {
+        sample.id = Some(cur.u64()?);
+    }
+    if sample_type & PERF_SAMPLE_STREAM_ID != 0 {
+        cur.skip(8)?;
+    }
+    if sample_type & PERF_SAMPLE_CPU != 0 {
+        sample.cpu = cur.u32()?;
+        cur.skip(4)?;
+    }
+    if sample_type & PERF_SAMPLE_PERIOD != 0 {
+        cur.skip(8)?;
+    }
+    if sample_type & PERF_SAMPLE_READ != 0 {
+        // The layout depends on read_format, which we never ask for.
+        return Some(sample);
+    }
+    if sample_type & PERF_SAMPLE_CALLCHAIN != 0 {
+        let nr = cur.u64()?;
+        cur.skip(nr as usize * 8)?;
+    }
+    if sample_type & PERF_SAMPLE_RAW != 0 {
+        let size = cur.u32()?;
+        sample.raw = cur.bytes(size as usize)?;
+    }
+    Some(sample)
+}
+
+/// Decode the `sample_id` fields that `sample_id_all` appends to records other than samples.
+/// Only the fields selected by `sample_type` are present, in the order below, at the very end of
+/// the record.
+pub fn parse_sample_id(body: &[u8], sample_type: u64) -> Option<Sample<'_>> {
+    let fields = [
+        PERF_SAMPLE_TID,
+        PERF_SAMPLE_TIME,
+        PERF_SAMPLE_ID,
+        PERF_SAMPLE_STREAM_ID,
+        PERF_SAMPLE_CPU,
+        PERF_SAMPLE_IDENTIFIER,
+    ];
+    let size = fields.iter().filter(|f| sample_type & **f != 0).count() * 8;
+    let mut cur = Cursor::new(body.get(body.len().checked_sub(size)?..)?);
+    let mut sample = Sample::default();
+    if sample_type & PERF_SAMPLE_TID != 0 {
+        sample.pid = cur.u32()?;
+        sample.tid = cur.u32()?;
+    }
+    if sample_type & PERF_SAMPLE_TIME != 0 {
+        sample.time = cur.u64()?;
+    }
+    if sample_type & PERF_SAMPLE_ID != 0 {
+        sample.id = Some(cur.u64()?);
+    }
+    if sample_type & PERF_SAMPLE_STREAM_ID != 0 {
+        cur.skip(8)?;
+    }
+    if sample_type & PERF_SAMPLE_CPU != 0 {
+        sample.cpu = cur.u32()?;
+        cur.skip(4)?;
+    }
+    if sample_type & PERF_SAMPLE_IDENTIFIER != 0 {
+        sample.id = Some(cur.u64()?);
+    }
+    Some(sample)
+}
+
+/// The identifier of a non-sample record written with `sample_id_all` and
+/// `PERF_SAMPLE_IDENTIFIER`, which is always its last word.
+pub fn record_identifier(body: &[u8]) -> Option<u64> {
+    let last = body.get(body.len().checked_sub(8)?..)?;
+    Some(u64::from_le_bytes(last.try_into().unwrap()))
+}
+
+/// The fields of a `PERF_RECORD_MMAP` or `PERF_RECORD_MMAP2` that we use.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmap<'a> {
+    pub pid: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub filename: &'a str,
+}
+
+/// Decode the body (without the header) of a `PERF_RECORD_MMAP` or `PERF_RECORD_MMAP2`.
+pub fn parse_mmap(kind: u32, body: &[u8]) -> Option<Mmap<'_>> {
+    let mut cur = Cursor::new(body);
+    let pid = cur.u32()?;
+    let tid = cur.u32()?;
+    let addr = cur.u64()?;
+    let len = cur.u64()?;
+    let pgoff = cur.u64()?;
+    if kind == PERF_RECORD_MMAP2 {
+        // maj, min, ino, ino_generation (or build id), prot, flags
+        cur.skip(32)?;
+    }
+    Some(Mmap {
+        pid,
+        tid,
+        addr,
+        len,
+        pgoff,
+        filename: cur.string()?,
+    })
+}
+
+/// The fields of a `PERF_RECORD_FORK` that we use.
+#[derive(Debug, Clone, Copy)]
+pub struct Fork {
+    pub pid: u32,
+    pub ppid: u32,
+    pub time: u64,
+}
+
+/// Decode the body (without the header) of a `PERF_RECORD_FORK`.
+pub fn parse_fork(body: &[u8]) -> Option<Fork> {
+    let mut cur = Cursor::new(body);
+    let pid = cur.u32()?;
+    let ppid = cur.u32()?;
+    cur.skip(8)?;
+    Some(Fork {
+        pid,
+        ppid,
+        time: cur.u64()?,
+    })
+}
+
+/// Tracepoint that reports munmap calls, which the kernel doesn't otherwise tell us about.
+pub const MUNMAP_TRACEPOINT: &str = "syscalls:sys_enter_munmap";
+
+/// Tracepoint for every user page fault, which unlike the fault events carries the error code.
+pub const PAGE_FAULT_TRACEPOINT: &str = "exceptions:page_fault_user";
+
+/// Error code bit set when the faulting access was a write.
+pub const PF_WRITE: u64 = 1 << 1;
+
+/// Decode the raw data of an `exceptions:page_fault_user` sample into (address, error_code). After
+/// the 8 bytes of common tracepoint fields come the address, ip and error code.
+pub fn parse_page_fault(raw: &[u8]) -> Option<(u64, u64)> {
+    let mut cur = Cursor::new(raw);
+    cur.skip(8)?;
+    let address = cur.u64()?;
+    cur.skip(8)?;
+    Some((address, cur.u64()?))
+}
+
+/// Decode the raw data of a `syscalls:sys_enter_munmap` sample into the unmapped (addr, len).
+/// The layout is fixed: the common tracepoint fields and the syscall number pad out to 16 bytes,
+/// followed by the two arguments.
+pub fn parse_munmap(raw: &[u8]) -> Option<(u64, u64)> {
+    let mut cur = Cursor::new(raw);
+    cur.skip(16)?;
+    Some((cur.u64()?, cur.u64()?))
+}
@@ -0,0 +1,371 @@
+//! Headless playback: drive the player against an off-screen buffer and write the frames out as
+//! an asciinema cast or an animated SVG, for attaching to bug reports without needing a terminal.
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use color_eyre::eyre::Result;
+use ratatui::{
+    Terminal,
+    backend::TestBackend,
+    buffer::{Buffer, Cell},
+    layout::Rect,
+    style::{Color, Modifier},
+};
+
+use crate::{
+    RenderCli, RenderFormat,
+    app::App,
+    event::{EventHandler, TICK_FPS},
+    perf::FaultData,
+};
+
+pub fn render(cli: &RenderCli) -> Result<()> {
+    let data = FaultData::open(cli.play.trace_file.as_deref().unwrap_or("pfviz".as_ref()))?;
+    let mut app = App::with_events(cli.play.clone(), data, EventHandler::headless());
+    app.ui.status.marker_a = cli.start;
+    app.ui.status.marker_b = cli.end;
+    app.ui.status.looping = false;
+    app.ui.status.paused = false;
+    app.goto_event(app.get_first_play_event());
+
+    let format = cli
+        .format
+        .unwrap_or(if cli.output.extension().is_some_and(|ext| ext == "svg") {
+            RenderFormat::Svg
+        } else {
+            RenderFormat::Cast
+        });
+    // Frames are written out as they're drawn, so that long renders don't pile up in memory.
+    let out = BufWriter::new(File::create(&cli.output)?);
+    let area = Rect::new(0, 0, cli.cols, cli.rows);
+    let mut writer: Box<dyn FrameWriter> = match format {
+        RenderFormat::Cast => Box::new(CastWriter::new(out, area)?),
+        RenderFormat::Svg => Box::new(SvgWriter::new(out, area)?),
+    };
+
+    let mut terminal = Terminal::new(TestBackend::new(cli.cols, cli.rows))?;
+    let mut frames = 0;
+    loop {
+        terminal.draw(|frame| frame.render_widget(&app, frame.area()))?;
+        writer.frame(terminal.backend().buffer())?;
+        frames += 1;
+        if app.ui.status.cur_event >= app.get_last_play_event() {
+            break;
+        }
+        if frames >= cli.max_frames {
+            tracing::warn!(
+                "stopping after {} frames, at event {}",
+                frames,
+                app.ui.status.cur_event
+            );
+            break;
+        }
+        app.tick();
+    }
+    writer.finish()?;
+    tracing::info!("wrote {} frames to {}", frames, cli.output.display());
+    Ok(())
+}
+
+/// Somewhere to write frames to, one at a time.
+trait FrameWriter {
+    fn frame(&mut self, frame: &Buffer) -> Result<()>;
+    /// Write out whatever comes after the last frame.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Writes an asciinema v2 cast. Each frame only redraws the rows that changed.
+struct CastWriter<W: Write> {
+    out: W,
+    prev: Option<Buffer>,
+    frames: usize,
+}
+
+impl<W: Write> CastWriter<W> {
+    fn new(mut out: W, area: Rect) -> Result<Self> {
+        let header = serde_json::json!({
+            "version": 2,
+            "width": area.width,
+            "height": area.height,
+            "env": { "TERM": "xterm-256color" },
+        });
+        writeln!(out, "{}", header)?;
+        Ok(Self {
+            out,
+            prev: None,
+            frames: 0,
+        })
+    }
+}
+
+impl<W: Write> FrameWriter for CastWriter<W> {
+    fn frame(&mut self, frame: &Buffer) -> Result<()> {
+        let mut data = String::new();
+        if self.prev.is_none() {
+            data += "\x1b[?25l\x1b[2J";
+        }
+        for y in 0..frame.area.height {
+            let row = |buf: &Buffer| {
+                (0..buf.area.width)
+                    .map(|x| buf[(x, y)].clone())
+                    .collect::<Vec<_>>()
+            };
+            let cells = row(frame);
+            if self.prev.as_ref().is_some_and(|prev| row(prev) == cells) {
+                continue;
+            }
+            write!(data, "\x1b[{};1H", y + 1)?;
+            let mut style = None;
+            for cell in &cells {
+                let sgr = sgr(cell);
+                if style.as_ref() != Some(&sgr) {
+                    write!(data, "\x1b[0;{}m", sgr)?;
+                    style = Some(sgr);
+                }
+                data += cell.symbol();
+            }
+            data += "\x1b[0m";
+        }
+        let time = self.frames as f64 / TICK_FPS;
+        writeln!(self.out, "{}", serde_json::json!([time, "o", data]))?;
+        match self.prev.as_mut() {
+            Some(prev) => prev.clone_from(frame),
+            None => self.prev = Some(frame.clone()),
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// SGR parameters for a cell's style.
+fn sgr(cell: &Cell) -> String {
+    let mut params = vec![ansi_color(cell.fg, false), ansi_color(cell.bg, true)];
+    for (modifier, param) in [
+        (Modifier::BOLD, "1"),
+        (Modifier::DIM, "2"),
+        (Modifier::ITALIC, "3"),
+        (Modifier::UNDERLINED, "4"),
+        (Modifier::REVERSED, "7"),
+    ] {
+        if cell.modifier.contains(modifier) {
+            params.push(param.to_string());
+        }
+    }
+    params.join(";")
+}
+
+fn ansi_color(color: Color, bg: bool) -> String {
+    let offset = if bg { 10 } else { 0 };
+    let base = match color {
+        Color::Reset => 39,
+        Color::Black => 30,
+        Color::Red => 31,
+        Color::Green => 32,
+        Color::Yellow => 33,
+        Color::Blue => 34,
+        Color::Magenta => 35,
+        Color::Cyan => 36,
+        Color::Gray => 37,
+        Color::DarkGray => 90,
+        Color::LightRed => 91,
+        Color::LightGreen => 92,
+        Color::LightYellow => 93,
+        Color::LightBlue => 94,
+        Color::LightMagenta => 95,
+        Color::LightCyan => 96,
+        Color::White => 97,
+        Color::Rgb(r, g, b) => return format!("{};2;{};{};{}", 38 + offset, r, g, b),
+        Color::Indexed(i) => return format!("{};5;{}", 38 + offset, i),
+    };
+    (base + offset).to_string()
+}
+
+const CELL_WIDTH: f64 = 8.4;
+const CELL_HEIGHT: f64 = 17.0;
+const FONT_SIZE: f64 = 14.0;
+const DEFAULT_FG: (u8, u8, u8) = (0xe5, 0xe5, 0xe5);
+const DEFAULT_BG: (u8, u8, u8) = (0, 0, 0);
+
+/// Writes an SVG that plays the frames in a loop. The frames are stacked in one tall strip that a
+/// CSS animation steps through, one frame height at a time. The animation comes last, since its
+/// length is only known once every frame is written.
+struct SvgWriter<W: Write> {
+    out: W,
+    height: f64,
+    frames: usize,
+}
+
+impl<W: Write> SvgWriter<W> {
+    fn new(mut out: W, area: Rect) -> Result<Self> {
+        let width = area.width as f64 * CELL_WIDTH;
+        let height = area.height as f64 * CELL_HEIGHT;
+        writeln!(
+            out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w:.1}" height="{h:.1}" viewBox="0 0 {w:.1} {h:.1}">"#,
+            w = width,
+            h = height
+        )?;
+        writeln!(
+            out,
+            r#"<rect width="100%" height="100%" fill="{}"/>"#,
+            hex(DEFAULT_BG)
+        )?;
+        writeln!(out, r#"<g id="strip">"#)?;
+        Ok(Self {
+            out,
+            height,
+            frames: 0,
+        })
+    }
+}
+
+impl<W: Write> FrameWriter for SvgWriter<W> {
+    fn frame(&mut self, frame: &Buffer) -> Result<()> {
+        let top = self.frames as f64 * self.height;
+        for y in 0..frame.area.height {
+            let baseline = top + (y as f64 + 0.8) * CELL_HEIGHT;
+            let mut x = 0;
+            while x < frame.area.width {
+                // Cells with the same colours are drawn as one run.
+                let style = svg_colors(&frame[(x, y)]);
+                let start = x;
+                let mut text = String::new();
+                while x < frame.area.width && svg_colors(&frame[(x, y)]) == style {
+                    text += frame[(x, y)].symbol();
+                    x += 1;
+                }
+                let (fg, bg, bold) = style;
+                let run_x = start as f64 * CELL_WIDTH;
+                let run_width = (x - start) as f64 * CELL_WIDTH;
+                if bg != DEFAULT_BG {
+                    writeln!(
+                        self.out,
+                        r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}"/>"#,
+                        run_x,
+                        top + y as f64 * CELL_HEIGHT,
+                        run_width,
+                        CELL_HEIGHT,
+                        hex(bg)
+                    )?;
+                }
+                if !text.trim().is_empty() {
+                    writeln!(
+                        self.out,
+                        r#"<text x="{:.1}" y="{:.1}" textLength="{:.1}" lengthAdjust="spacingAndGlyphs" fill="{}"{}>{}</text>"#,
+                        run_x,
+                        baseline,
+                        run_width,
+                        hex(fg),
+                        if bold { r#" font-weight="bold""# } else { "" },
+                        escape(&text)
+                    )?;
+                }
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        writeln!(self.out, "</g>")?;
+        writeln!(
+            self.out,
+            "<style>#strip {{ animation: play {:.3}s steps({}) infinite; }} \
+             @keyframes play {{ to {{ transform: translateY(-{:.1}px); }} }} \
+             text {{ font-family: monospace; font-size: {}px; white-space: pre; }}</style>",
+            self.frames as f64 / TICK_FPS,
+            self.frames.max(1),
+            self.frames as f64 * self.height,
+            FONT_SIZE
+        )?;
+        writeln!(self.out, "</svg>")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Foreground and background colours and boldness of a cell, with reversal applied.
+fn svg_colors(cell: &Cell) -> ((u8, u8, u8), (u8, u8, u8), bool) {
+    let fg = rgb(cell.fg).unwrap_or(DEFAULT_FG);
+    let bg = rgb(cell.bg).unwrap_or(DEFAULT_BG);
+    let (fg, bg) = if cell.modifier.contains(Modifier::REVERSED) {
+        (bg, fg)
+    } else {
+        (fg, bg)
+    };
+    (fg, bg, cell.modifier.contains(Modifier::BOLD))
+}
+
+/// The xterm colour for `color`, or `None` for the default colour.
+pub fn rgb(color: Color) -> Option<(u8, u8, u8)> {
+    const ANSI: [(u8, u8, u8); 16] = [
+        (0x00, 0x00, 0x00),
+        (0xcd, 0x00, 0x00),
+        (0x00, 0xcd, 0x00),
+        (0xcd, 0xcd, 0x00),
+        (0x00, 0x00, 0xee),
+        (0xcd, 0x00, 0xcd),
+        (0x00, 0xcd, 0xcd),
+        (0xe5, 0xe5, 0xe5),
+        (0x7f, 0x7f, 0x7f),
+        (0xff, 0x00, 0x00),
+        (0x00, 0xff, 0x00),
+        (0xff, 0xff, 0x00),
+        (0x5c, 0x5c, 0xff),
+        (0xff, 0x00, 0xff),
+        (0x00, 0xff, 0xff),
+        (0xff, 0xff, 0xff),
+    ];
+    let index = match color {
+        Color::Reset => return None,
+        Color::Rgb(r, g, b) => return Some((r, g, b)),
+        Color::Indexed(i) => i,
+        Color::Black => 0,
+        Color::Red => 1,
+        Color::Green => 2,
+        Color::Yellow => 3,
+        Color::Blue => 4,
+        Color::Magenta => 5,
+        Color::Cyan => 6,
+        Color::Gray => 7,
+        Color::DarkGray => 8,
+        Color::LightRed => 9,
+        Color::LightGreen => 10,
+        Color::LightYellow => 11,
+        Color::LightBlue => 12,
+        Color::LightMagenta => 13,
+        Color::LightCyan => 14,
+        Color::White => 15,
+    };
+    Some(match index {
+        0..16 => ANSI[index as usize],
+        16..232 => {
+            let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+            let n = index - 16;
+            (level(n / 36), level(n / 6 % 6), level(n % 6))
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            (gray, gray, gray)
+        }
+    })
+}
+
+fn hex((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -1,55 +1,132 @@
-use std::time::Duration;
+use std::{ops::Range, time::Duration};
 
 use ratatui::{
     layout::{Constraint, Direction, Flex, Layout},
-    style::{Color, Style},
+    style::{Style, Stylize},
+    text::Line,
     widgets::{Block, Borders, Sparkline, SparklineBar, Widget},
 };
 
 use crate::{
-    perf::{EventKind, EventRecord, FaultData},
-    ui::{CACHE_MAX, CACHE_SET, FaultProcessResult, FileVis, RegionInfo},
+    ColorMode,
+    perf::{EventKind, EventRecord, FaultData, PAGE_SIZE},
+    ui::{CACHE_MAX, CACHE_SET, DIM_COLORS, FaultProcessResult, FileVis, decay, fault_colors},
 };
 
+/// How many parts the range being looked at is split into.
+const COMPONENTS: u64 = 8;
+/// Most bars drawn for each part. Bars cover a power of two number of pages.
+const BARS: u64 = 128;
+
 #[derive(Debug)]
 pub struct SingleFileVis {
     components: Vec<FileComponent>,
     objid: usize,
+    name: String,
+    /// The ranges zoomed into, outermost first. The last one is shown.
+    zoom: Vec<Range<u64>>,
+    /// The component that Enter zooms into.
+    cursor: usize,
 }
 
 impl SingleFileVis {
     pub fn new(fv: &FileVis) -> Self {
-        let mut components = Vec::new();
-
-        if components.is_empty() {
-            let mut start = 0;
-            let ps = 0x1000 * 512;
-            let len = fv.end_off / 8;
-            for _ in 0..8 {
-                let name = format!("{} ({} - {})", fv.name, start, start + len);
-                components.push(FileComponent::new(name, ps, start, len, fv.objid));
-                start += len;
-            }
-        }
-        Self {
-            components,
+        let whole = fv.start_off..fv.end_off.max(fv.start_off + PAGE_SIZE);
+        let mut sf = Self {
+            components: Vec::new(),
             objid: fv.objid,
-        }
+            name: fv.name.clone(),
+            zoom: Vec::from([whole]),
+            cursor: 0,
+        };
+        sf.split();
+        sf
+    }
+
+    /// Split the range being looked at into components.
+    fn split(&mut self) {
+        let range = self.range();
+        let len = (range.end - range.start)
+            .div_ceil(COMPONENTS)
+            .next_multiple_of(PAGE_SIZE);
+        let page_size = (len / BARS).max(PAGE_SIZE).next_power_of_two();
+        self.components = (range.start..range.end)
+            .step_by(len as usize)
+            .map(|start| {
+                let len = len.min(range.end - start);
+                let name = format!(
+                    "{} [{}-{})",
+                    self.name,
+                    humansize::format_size(start, humansize::BINARY),
+                    humansize::format_size(start + len, humansize::BINARY)
+                );
+                FileComponent::new(name, page_size, start, len, self.objid)
+            })
+            .collect();
+        self.cursor = self.cursor.min(self.components.len() - 1);
     }
 
     pub fn obj_id(&self) -> usize {
         self.objid
     }
 
-    pub fn calculate_decay(&mut self, time: Duration) {
+    /// The byte range of the file being looked at.
+    pub fn range(&self) -> Range<u64> {
+        self.zoom.last().unwrap().clone()
+    }
+
+    pub fn move_cursor(&mut self, up: bool) {
+        self.cursor = if up {
+            self.cursor.saturating_sub(1)
+        } else {
+            (self.cursor + 1).min(self.components.len() - 1)
+        };
+    }
+
+    /// Look at just the component under the cursor. Returns whether the view changed, which
+    /// isn't the case once the component is a single page.
+    pub fn zoom_in(&mut self) -> bool {
+        let comp = &self.components[self.cursor];
+        if comp.len <= PAGE_SIZE {
+            return false;
+        }
+        self.zoom.push(comp.start..comp.start + comp.len);
+        self.cursor = 0;
+        self.split();
+        true
+    }
+
+    /// Go back to the range zoomed in from, keeping the cursor on where we were. Returns false if
+    /// already looking at the whole file.
+    pub fn zoom_out(&mut self) -> bool {
+        if self.zoom.len() == 1 {
+            return false;
+        }
+        let start = self.zoom.pop().unwrap().start;
+        self.split();
+        self.cursor = self
+            .components
+            .iter()
+            .position(|comp| comp.start + comp.len > start)
+            .unwrap_or(0);
+        true
+    }
+
+    pub fn calculate_decay(&mut self, time: Duration, half_life: Duration) {
         for comp in &mut self.components {
-            comp.calculate_decay(time);
+            comp.calculate_decay(time, half_life);
         }
     }
 
-    pub fn fault(&mut self, faults: &[EventRecord], data: &FaultData) -> FaultProcessResult {
+    pub fn fault(
+        &mut self,
+        faults: &[EventRecord],
+        data: &FaultData,
+        mode: ColorMode,
+        dim: bool,
+    ) -> FaultProcessResult {
         for comp in &mut self.components {
-            comp.fault(faults, data);
+            comp.fault(faults, data, mode, dim);
         }
 
         FaultProcessResult {
@@ -63,6 +140,30 @@ impl SingleFileVis {
             comp.reset();
         }
     }
+
+    pub fn snapshot(&self) -> Vec<FileComponent> {
+        self.components.clone()
+    }
+
+    pub fn restore(&mut self, components: &[FileComponent]) {
+        self.components.clone_from_slice(components);
+    }
+
+    /// Rough size of a snapshot, in bytes.
+    pub fn state_size(&self) -> usize {
+        self.components
+            .iter()
+            .map(|comp| {
+                (comp.faultdata.len() + comp.cachedata.len()) * size_of::<PageInfo>()
+                    + comp.touched.len() * size_of::<u64>()
+            })
+            .sum()
+    }
+
+    /// The file's name, as shown.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 impl Widget for &SingleFileVis {
@@ -70,58 +171,41 @@ impl Widget for &SingleFileVis {
     where
         Self: Sized,
     {
-        const MAX_V: usize = 32;
-        let vcount = self.components.len().min(MAX_V);
+        let [header, area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(area);
+        let range = self.range();
+        let page_size = self.components.first().map_or(PAGE_SIZE, |c| c.page_size);
+        Line::from(format!(
+            "{} [{}-{}), zoom level {}, {} per bar. (Up/Down) Pick Range; (Enter) Zoom In; (Esc) Zoom Out",
+            self.name,
+            humansize::format_size(range.start, humansize::BINARY),
+            humansize::format_size(range.end, humansize::BINARY),
+            self.zoom.len(),
+            humansize::format_size(page_size, humansize::BINARY)
+        ))
+        .render(header, buf);
+
         let layout = Layout::new(
             Direction::Vertical,
-            Constraint::from_lengths(vec![4u16; vcount]),
+            Constraint::from_lengths(vec![4u16; self.components.len()]),
         )
         .flex(Flex::SpaceAround);
         let splits = layout.split(area);
 
         for (idx, fv) in self.components.iter().enumerate() {
-            let area = &splits[idx];
-            fv.render(*area, buf);
+            fv.render_bars(splits[idx], buf, idx == self.cursor);
         }
     }
 }
 
-impl Widget for &FileComponent {
-    fn render(self, area: ratatui::prelude::Rect, buf: &mut ratatui::prelude::Buffer)
-    where
-        Self: Sized,
-    {
-        let block = Block::new()
-            .title(self.name.as_str())
-            .borders(Borders::ALL)
-            .title_bottom(format!(
-                "{} pages, {}/{} f/m",
-                self.len / self.page_size,
-                self.faults,
-                self.misses
-            ));
-
-        let inner = block.inner(area);
-        let inner_layout = Layout::new(
-            Direction::Vertical,
-            &[Constraint::Length(1), Constraint::Length(1)],
-        );
-        let splits = inner_layout.split(inner);
-
-        let fault_sparkline = Sparkline::default().max(CACHE_MAX).data(&self.faultdata);
-        let cache_sparkline = Sparkline::default().max(CACHE_MAX).data(&self.cachedata);
-        block.render(area, buf);
-        cache_sparkline.render(splits[0], buf);
-        fault_sparkline.render(splits[1], buf);
-    }
-}
-
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FileComponent {
     name: String,
     page_size: u64,
     faultdata: Vec<PageInfo>,
     cachedata: Vec<PageInfo>,
+    /// One bit for each page in the component, set once the page has had an event.
+    touched: Vec<u64>,
     faults: usize,
     misses: usize,
     start: u64,
@@ -131,12 +215,15 @@ pub struct FileComponent {
 
 impl FileComponent {
     pub fn new(name: String, page_size: u64, start: u64, len: u64, objid: usize) -> Self {
+        let bars = len.div_ceil(page_size) as usize;
+        let pages = len.div_ceil(PAGE_SIZE) as usize;
         Self {
             name,
             objid,
             page_size,
-            faultdata: Vec::new(),
-            cachedata: Vec::new(),
+            faultdata: vec![PageInfo::default(); bars],
+            cachedata: vec![PageInfo::default(); bars],
+            touched: vec![0; pages.div_ceil(64)],
             faults: 0,
             misses: 0,
             start,
@@ -145,59 +232,78 @@ impl FileComponent {
     }
 
     pub fn reset(&mut self) {
-        self.faultdata.clear();
-        self.cachedata.clear();
+        self.faultdata.fill(PageInfo::default());
+        self.cachedata.fill(PageInfo::default());
+        self.touched.fill(0);
         self.misses = 0;
         self.faults = 0;
     }
 
-    fn calculate_decay(&mut self, time: Duration) {
-        for page in &mut self.cachedata {
-            if page.time >= time {
-                continue;
-            }
+    fn render_bars(
+        &self,
+        area: ratatui::prelude::Rect,
+        buf: &mut ratatui::prelude::Buffer,
+        highlighted: bool,
+    ) {
+        let touched = self.touched.iter().map(|w| w.count_ones()).sum::<u32>();
+        let block = Block::new()
+            .title(self.name.as_str())
+            .title_style(if highlighted {
+                Style::default().bold()
+            } else {
+                Style::default()
+            })
+            .borders(Borders::ALL)
+            .title_bottom(format!(
+                "{}/{} pages touched, {} bars, {}/{} f/m",
+                touched,
+                self.len.div_ceil(PAGE_SIZE),
+                humansize::format_size(self.page_size, humansize::BINARY),
+                self.faults,
+                self.misses
+            ));
 
-            let decay_max = Duration::from_millis(1000);
-            let diff = decay_max - (time - page.time).min(decay_max);
+        let inner = block.inner(area);
+        let inner_layout = Layout::new(
+            Direction::Vertical,
+            [Constraint::Length(1), Constraint::Length(1)],
+        );
+        let splits = inner_layout.split(inner);
 
-            let micros = diff.as_micros() as u64;
-            if page.value.is_some() {
-                page.value = Some(micros / 100);
-            }
-            if let Some(v) = page.value {
-                if v > 0 {
-                    //      page.value = Some(v - 100);
-                }
-            }
-        }
-        for page in &mut self.faultdata {
+        let fault_sparkline = Sparkline::default().max(CACHE_MAX).data(&self.faultdata);
+        let cache_sparkline = Sparkline::default().max(CACHE_MAX).data(&self.cachedata);
+        block.render(area, buf);
+        cache_sparkline.render(splits[0], buf);
+        fault_sparkline.render(splits[1], buf);
+    }
+
+    fn calculate_decay(&mut self, time: Duration, half_life: Duration) {
+        for page in self.cachedata.iter_mut().chain(&mut self.faultdata) {
             if page.time >= time {
                 continue;
             }
-
-            let decay_max = Duration::from_millis(1000);
-            let diff = decay_max - (time - page.time).min(decay_max);
-
-            let micros = diff.as_micros() as u64;
             if page.value.is_some() {
-                page.value = Some(micros / 100);
-            }
-            if let Some(v) = page.value {
-                if v > 0 {
-                    //page.value = Some(v - 1);
-                }
+                page.value = Some(decay(CACHE_SET, time - page.time, half_life));
             }
         }
     }
 
-    pub fn fault(&mut self, faults: &[EventRecord], _fd: &FaultData) -> FaultProcessResult {
-        for (idx, fault) in faults.iter().enumerate() {
+    pub fn fault(
+        &mut self,
+        faults: &[EventRecord],
+        _fd: &FaultData,
+        mode: ColorMode,
+        dim: bool,
+    ) -> FaultProcessResult {
+        for fault in faults {
             if fault.obj_id() != self.objid {
                 continue;
             }
             if fault.offset() >= self.start + self.len || fault.offset() < self.start {
                 continue;
             }
+            let page = ((fault.offset() - self.start) / PAGE_SIZE) as usize;
+            self.touched[page / 64] |= 1 << (page % 64);
             let pos = ((fault.offset() - self.start) / self.page_size) as usize;
             let region_vec = if fault.kind().is_miss() {
                 self.misses += 1;
@@ -206,10 +312,6 @@ impl FileComponent {
                 self.faults += 1;
                 &mut self.faultdata
             };
-            if pos >= region_vec.len() {
-                region_vec.resize_with(pos + 1, || PageInfo::default());
-                continue;
-            }
 
             let mut has_recent_major = if let Some(count) = &mut region_vec[pos].has_major {
                 if *count == 0 {
@@ -231,25 +333,14 @@ impl FileComponent {
                 region_vec[pos].has_major = None;
             }
 
-            let mut colors = if fault.kind() == EventKind::MajorFault {
-                (Color::LightRed, Color::Red)
-            } else if has_recent_major {
-                (Color::LightMagenta, Color::Magenta)
+            let colors = if dim {
+                DIM_COLORS
             } else {
-                (Color::LightBlue, Color::Blue)
+                fault_colors(fault, has_recent_major, mode)
             };
 
-            if fault.kind().is_miss() {
-                colors = (Color::LightGreen, Color::Green);
-            }
-
-            region_vec[pos as usize] =
-                PageInfo::new(fault, Style::default().fg(colors.0).bg(colors.1));
-            region_vec[pos as usize].value = Some(if fault.kind().is_miss() {
-                CACHE_SET
-            } else {
-                CACHE_SET
-            });
+            region_vec[pos] = PageInfo::new(fault, Style::default().fg(colors.0).bg(colors.1));
+            region_vec[pos].value = Some(CACHE_SET);
         }
         FaultProcessResult {
             count: faults.len(),
@@ -258,7 +349,8 @@ impl FileComponent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
+#[allow(dead_code)]
 struct PageInfo {
     last_addr: u64,
     value: Option<u64>,
@@ -279,8 +371,8 @@ impl PageInfo {
     }
 }
 
-impl Into<SparklineBar> for &PageInfo {
-    fn into(self) -> SparklineBar {
-        SparklineBar::from(self.value).style(self.style)
+impl From<&PageInfo> for SparklineBar {
+    fn from(value: &PageInfo) -> Self {
+        SparklineBar::from(value.value).style(value.style)
     }
 }
@@ -0,0 +1,201 @@
+//! Resolving the instruction pointers of events to the functions and source lines they're in, from
+//! the symbol tables and DWARF debug info of the binaries mapped at them. This is done when a trace
+//! is written, while the binaries are at hand, and the results are kept in the trace.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{self, Display},
+    fs::File,
+};
+
+use addr2line::Loader;
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+use serde::{Deserialize, Serialize};
+
+use crate::perf::Interner;
+
+/// Where an instruction is. Strings are interned in the trace's string table.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeLocation {
+    /// The binary mapped at the instruction, if it was mapped.
+    pub binary: Option<u32>,
+    /// Offset of the instruction within the binary's file.
+    pub offset: u64,
+    /// The function the instruction is in, demangled. For inlined code this is the function that
+    /// was inlined.
+    pub function: Option<u32>,
+    pub source_file: Option<u32>,
+    pub line: Option<u32>,
+}
+
+/// Where an instruction was found while resolving events, before it's symbolized.
+#[derive(Debug, Clone, Copy)]
+pub struct CodeSite {
+    pub ip: u64,
+    /// The binary mapped at the instruction and the offset into it.
+    pub mapped: Option<(u32, u64)>,
+    /// The symbol perf gave the instruction, if any, for when the binary can't be read.
+    pub symbol: Option<u32>,
+}
+
+/// Symbolize each of the instructions in `sites`, opening each binary once. Returns their
+/// locations in the same order.
+pub fn resolve(sites: &[CodeSite], strings: &mut Interner) -> Vec<CodeLocation> {
+    let mut by_binary: HashMap<Option<u32>, Vec<(usize, &CodeSite)>> = HashMap::new();
+    for (idx, site) in sites.iter().enumerate() {
+        by_binary
+            .entry(site.mapped.map(|(binary, _)| binary))
+            .or_default()
+            .push((idx, site));
+    }
+    tracing::info!("symbolizing code in {} binaries", by_binary.len());
+
+    let mut code = vec![None; sites.len()];
+    for (binary, sites) in by_binary {
+        let path = binary.and_then(|b| strings.resolve(b)).map(str::to_string);
+        let symbolizer = path.as_deref().and_then(|path| {
+            Symbolizer::open(path)
+                .inspect_err(|e| tracing::debug!("not symbolizing {}: {}", path, e))
+                .ok()
+        });
+        for (idx, site) in sites {
+            let offset = site.mapped.map_or(site.ip, |(_, offset)| offset);
+            let mut location = CodeLocation {
+                binary,
+                offset,
+                function: None,
+                source_file: None,
+                line: None,
+            };
+            if let Some(symbolizer) = &symbolizer {
+                symbolizer.locate(offset, &mut location, strings);
+            }
+            if location.function.is_none() {
+                location.function = site.symbol;
+            }
+            code[idx] = Some(location);
+        }
+    }
+    code.into_iter().flatten().collect()
+}
+
+struct Symbolizer {
+    loader: Loader,
+    /// The file offset, length and virtual address of each loadable segment.
+    segments: Vec<(u64, u64, u64)>,
+    /// The address, size and demangled name of each function symbol, sorted by address.
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl Symbolizer {
+    fn open(path: &str) -> color_eyre::Result<Self> {
+        let file = File::open(path)?;
+        let map = unsafe { memmap2::Mmap::map(&file) }?;
+        let object = object::File::parse(&*map)?;
+        let segments = object
+            .segments()
+            .map(|seg| {
+                let (offset, len) = seg.file_range();
+                (offset, len, seg.address())
+            })
+            .collect();
+        // Stripped binaries only have their dynamic symbols.
+        let mut symbols = object
+            .symbols()
+            .chain(object.dynamic_symbols())
+            .filter(|sym| sym.kind() == SymbolKind::Text && sym.size() > 0)
+            .filter_map(|sym| {
+                let name = addr2line::demangle_auto(Cow::from(sym.name().ok()?), None);
+                Some((sym.address(), sym.size(), name.into_owned()))
+            })
+            .collect::<Vec<_>>();
+        symbols.sort();
+        symbols.dedup_by_key(|sym| sym.0);
+        let loader = Loader::new(path).map_err(|e| color_eyre::eyre::eyre!(e.to_string()))?;
+        Ok(Self {
+            loader,
+            segments,
+            symbols,
+        })
+    }
+
+    /// The function symbol covering `addr`. Unlike the nearest symbol before it, this doesn't
+    /// blame code in a stripped binary on whichever exported function happens to come first.
+    fn symbol(&self, addr: u64) -> Option<&str> {
+        let idx = self.symbols.partition_point(|(start, _, _)| *start <= addr);
+        let (start, size, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        (addr < start + size).then_some(name.as_str())
+    }
+
+    /// Fill in what the binary knows about the instruction at `offset` in its file.
+    fn locate(&self, offset: u64, location: &mut CodeLocation, strings: &mut Interner) {
+        // Debug info is in terms of where the binary asks to be loaded, not file offsets.
+        let Some(probe) = self
+            .segments
+            .iter()
+            .find(|(start, len, _)| (*start..start + len).contains(&offset))
+            .map(|(start, _, addr)| addr + (offset - start))
+        else {
+            return;
+        };
+
+        if let Ok(mut frames) = self.loader.find_frames(probe) {
+            // The first frame is the innermost, which is where the instruction really is.
+            if let Ok(Some(frame)) = frames.next() {
+                if let Some(name) = frame.function.as_ref().and_then(|f| f.demangle().ok()) {
+                    location.function = Some(strings.get_or_intern(&name));
+                }
+                if let Some(loc) = frame.location {
+                    location.source_file = loc.file.map(|file| strings.get_or_intern(file));
+                    location.line = loc.line;
+                }
+            }
+        }
+        if location.function.is_none()
+            && let Some(name) = self.symbol(probe)
+        {
+            location.function = Some(strings.get_or_intern(name));
+        }
+        if location.source_file.is_none()
+            && let Ok(Some(loc)) = self.loader.find_location(probe)
+        {
+            location.source_file = loc.file.map(|file| strings.get_or_intern(file));
+            location.line = loc.line;
+        }
+    }
+}
+
+/// A [`CodeLocation`] with its strings resolved, for display.
+pub struct ShowLocation<'a> {
+    pub ip: u64,
+    pub location: Option<&'a CodeLocation>,
+    pub strings: &'a Interner,
+}
+
+impl Display for ShowLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(location) = self.location else {
+            return write!(f, "{:#x}", self.ip);
+        };
+        let string = |id: Option<u32>| id.and_then(|id| self.strings.resolve(id));
+        match (string(location.function), string(location.binary)) {
+            (Some(function), _) => write!(f, "{}", function)?,
+            (None, Some(binary)) => write!(
+                f,
+                "{}+{:#x}",
+                binary.rsplit('/').next().unwrap_or(binary),
+                location.offset
+            )?,
+            (None, None) => write!(f, "{:#x}", self.ip)?,
+        }
+        if let Some(file) = string(location.source_file) {
+            write!(f, " ({}", file)?;
+            if let Some(line) = location.line {
+                write!(f, ":{}", line)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! The rate of events over the whole trace, as a histogram split by kind, for finding the
+//! interesting phases of a trace and scrubbing to them.
+
+use std::{cell::Cell, time::Duration};
+
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, Widget},
+};
+
+use crate::perf::{EventKind, FaultData};
+
+/// Time slices the trace is counted in. Columns of the histogram add up several.
+const BUCKETS: usize = 4096;
+/// Histogram colours from the bottom up, as in the legend.
+const KINDS: [(&str, Color); 3] = [
+    ("major", Color::Red),
+    ("minor", Color::Blue),
+    ("miss", Color::Green),
+];
+const EIGHTHS: [&str; 9] = [" ", "▁", "▂", "▃", "▄", "▅", "▆", "▇", "█"];
+
+#[derive(Debug)]
+pub struct Timeline {
+    /// Events of each kind (see [`KINDS`]) in each slice of the trace.
+    counts: Vec<[u64; 3]>,
+    end_time: Duration,
+    /// Columns drawn last time, which is what scrubbing steps through.
+    columns: Cell<usize>,
+}
+
+/// Where the playback position and markers are, to draw on the timeline.
+#[derive(Clone, Copy, Debug)]
+pub struct TimelineMarks {
+    pub now: Duration,
+    pub marker_a: Option<Duration>,
+    pub marker_b: Option<Duration>,
+}
+
+impl Timeline {
+    pub fn new(data: &FaultData, end_time: Duration) -> Self {
+        let mut counts = vec![[0; 3]; BUCKETS];
+        for fault in data.records.slice() {
+            let kind = match fault.kind() {
+                EventKind::MajorFault => 0,
+                EventKind::MinorFault => 1,
+                _ => 2,
+            };
+            counts[bucket(fault.time(), end_time, BUCKETS)][kind] += 1;
+        }
+        Self {
+            counts,
+            end_time,
+            columns: Cell::new(BUCKETS),
+        }
+    }
+
+    /// How much trace time one column of the histogram covers.
+    pub fn column_time(&self) -> Duration {
+        self.end_time / self.columns.get().max(1) as u32
+    }
+
+    /// Events of each kind per bucket in each of `columns` columns. Columns can cover different
+    /// numbers of buckets, so they're averaged to keep the heights comparable.
+    fn rates(&self, columns: usize) -> Vec<[f64; 3]> {
+        (0..columns)
+            .map(|col| {
+                let start = col * BUCKETS / columns;
+                let end = ((col + 1) * BUCKETS / columns).max(start + 1);
+                let mut rate = [0.0; 3];
+                for counts in &self.counts[start..end] {
+                    for (rate, count) in rate.iter_mut().zip(counts) {
+                        *rate += *count as f64;
+                    }
+                }
+                rate.map(|r| r / (end - start) as f64)
+            })
+            .collect()
+    }
+
+    pub fn render(&self, area: Rect, buf: &mut Buffer, marks: TimelineMarks) {
+        let inner_width = usize::from(area.width.saturating_sub(2)).max(1);
+        self.columns.set(inner_width);
+        let rates = self.rates(inner_width);
+        let peak = rates
+            .iter()
+            .map(|rate| rate.iter().sum::<f64>())
+            .fold(0.0, f64::max);
+        let bucket_time = self.end_time.as_secs_f64() / BUCKETS as f64;
+        let peak_per_sec = if bucket_time > 0.0 {
+            peak / bucket_time
+        } else {
+            0.0
+        };
+
+        let mut title = vec![Span::raw(format!(
+            "Timeline (peak {:.0} events/s: ",
+            peak_per_sec
+        ))];
+        for (label, color) in KINDS {
+            title.push(Span::styled("█", Style::default().fg(color)));
+            title.push(Span::raw(format!(" {} ", label)));
+        }
+        title.push(Span::raw(")"));
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(Line::from(title))
+            .title_bottom("([/]) Scrub; ({/}) Scrub x10");
+        let inner = block.inner(area);
+        block.render(area, buf);
+        if inner.height < 2 || inner.width == 0 {
+            return;
+        }
+        // The top row is for the markers, and the rest for the histogram.
+        let marker_row = inner.y;
+        let inner = Rect {
+            y: inner.y + 1,
+            height: inner.height - 1,
+            ..inner
+        };
+
+        let rows = usize::from(inner.height);
+        for (x, rate) in rates.iter().enumerate() {
+            let total = rate.iter().sum::<f64>();
+            if peak <= 0.0 || total <= 0.0 {
+                continue;
+            }
+            let height = (total / peak * (rows * 8) as f64).round().max(1.0) as usize;
+            // The kinds are stacked from the bottom in eighths of a row. Each cell takes the
+            // colour of whichever kind covers most of it.
+            let mut bounds = [0; 3];
+            let mut acc = 0.0;
+            for (bound, r) in bounds.iter_mut().zip(rate) {
+                acc += r;
+                *bound = (acc / total * height as f64).round() as usize;
+            }
+            for row in 0..rows {
+                let bottom = row * 8;
+                if bottom >= height {
+                    break;
+                }
+                let top = (bottom + 8).min(height);
+                let mut below = bottom;
+                let (kind, _) = bounds
+                    .iter()
+                    .map(|bound| {
+                        let covered = (*bound).clamp(bottom, top) - below.clamp(bottom, top);
+                        below = *bound;
+                        covered
+                    })
+                    .enumerate()
+                    .max_by_key(|(_, covered)| *covered)
+                    .unwrap();
+                buf[(inner.x + x as u16, inner.bottom() - 1 - row as u16)]
+                    .set_symbol(EIGHTHS[top - bottom])
+                    .set_fg(KINDS[kind].1);
+            }
+        }
+
+        // The markers are joined by a line above the histogram, and the playback position is a
+        // line through it.
+        let column = |time: Duration| inner.x + bucket(time, self.end_time, inner_width) as u16;
+        if let (Some(a), Some(b)) = (marks.marker_a, marks.marker_b) {
+            for x in column(a.min(b))..=column(a.max(b)) {
+                buf[(x, marker_row)].set_symbol("─").set_fg(Color::Yellow);
+            }
+        }
+        for (marker, label) in [(marks.marker_a, "A"), (marks.marker_b, "B")] {
+            if let Some(time) = marker {
+                buf[(column(time), marker_row)]
+                    .set_symbol(label)
+                    .set_style(Style::default().bold().fg(Color::Yellow));
+            }
+        }
+        let x = column(marks.now);
+        buf[(x, marker_row)].set_symbol("▼").set_fg(Color::White);
+        for y in inner.top()..inner.bottom() {
+            let cell = &mut buf[(x, y)];
+            if cell.symbol() == " " {
+                cell.set_symbol("│").set_fg(Color::White);
+            } else {
+                cell.set_bg(Color::White);
+            }
+        }
+    }
+}
+
+/// Which of `count` equal slices of the trace `time` falls in.
+fn bucket(time: Duration, end_time: Duration, count: usize) -> usize {
+    if end_time.is_zero() {
+        return 0;
+    }
+    ((time.as_secs_f64() / end_time.as_secs_f64() * count as f64) as usize).min(count - 1)
+}
@@ -1,89 +1,265 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::BufReader,
+    os::unix::process::ExitStatusExt,
     path::PathBuf,
-    process::{Command, Stdio},
+    process::{Command, ExitStatus},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use color_eyre::eyre::{Result, bail};
 
-use crate::{TraceCli, perf::EventKind};
+use crate::{
+    CaptureBackend, ImportCli, TraceCli,
+    container::{self, CaptureMeta},
+    perf::{EventKind, PerfData},
+};
 
-pub fn trace(cli: &TraceCli) -> Result<()> {
-    let mut command = Command::new("perf");
-    command
-        .arg("record")
-        .arg("-Tda")
-        .arg("-c")
-        .arg("1")
-        .arg("--all-user");
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
+/// Ctrl-C handler: stop an attached trace.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// perf record exits by re-raising the signal that stopped it, which is how an attached trace
+/// normally ends (the user presses Ctrl-C).
+fn was_interrupted(status: &ExitStatus) -> bool {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    matches!(status.signal(), Some(SIGINT | SIGTERM))
+}
+
+fn parse_event_args(events: &[String]) -> Result<HashMap<&str, EventKind>> {
     let mut ev_map = HashMap::new();
-    for event in &cli.events {
+    for event in events {
         let split = event.split(",").collect::<Vec<_>>();
         if split.len() != 2 {
             bail!(
                 "EVENT must be of the form `<perf-event>,<type>'. <type> may be one of miss,major,minor."
             );
         }
-        command.arg("-e").arg(split[0]);
-
         ev_map.insert(split[0], EventKind::from(split[1]));
     }
+    Ok(ev_map)
+}
+
+pub fn trace(cli: &TraceCli) -> Result<()> {
+    let attach = cli.pid.or(cli.tid);
+    if attach.is_some() && !cli.command.is_empty() {
+        bail!("COMMAND cannot be given together with --pid or --tid");
+    }
+    if attach.is_none() && cli.command.is_empty() {
+        bail!("either COMMAND or one of --pid or --tid must be given");
+    }
+
+    let ev_map = parse_event_args(&cli.events)?;
+
+    let (perf_data, backend) = match cli.backend {
+        CaptureBackend::Native => (
+            crate::native::Capture::open(cli, &ev_map)?.run(&ev_map, scratch_file(cli)?)?,
+            CaptureBackend::Native,
+        ),
+        CaptureBackend::Perf => (trace_perf(cli, &ev_map)?, CaptureBackend::Perf),
+        CaptureBackend::Auto => match crate::native::Capture::open(cli, &ev_map) {
+            Ok(capture) => (
+                capture.run(&ev_map, scratch_file(cli)?)?,
+                CaptureBackend::Native,
+            ),
+            Err(e) => {
+                tracing::warn!("native capture unavailable ({}), falling back to perf", e);
+                (trace_perf(cli, &ev_map)?, CaptureBackend::Perf)
+            }
+        },
+    };
+
+    let meta = CaptureMeta {
+        command: cli.command.clone(),
+        pid: cli.pid,
+        tid: cli.tid,
+        events: cli.events.clone(),
+        ..CaptureMeta::new(&backend.to_string())
+    };
+    write_output(&perf_data, &meta, cli.output.as_ref())
+}
+
+/// Convert an existing perf.data file, or saved `perf script` output, into a trace.
+pub fn import(cli: &ImportCli) -> Result<()> {
+    let ev_map = parse_event_args(&cli.events)?;
+    let perf_data = if crate::perf_data::is_perf_data(&cli.input) {
+        crate::perf_data::read_perf_data(&cli.input, &ev_map, &[])?
+    } else {
+        tracing::info!(
+            "{} is not a perf.data file, reading it as `perf script` output",
+            cli.input.display()
+        );
+        let file = File::open(&cli.input)?;
+        crate::perf::parse_perf_data(BufReader::new(file), ev_map, &[])?
+    };
+    let meta = CaptureMeta {
+        events: cli.events.clone(),
+        input: Some(cli.input.clone()),
+        ..CaptureMeta::new("import")
+    };
+    write_output(&perf_data, &meta, cli.output.as_ref())
+}
+
+fn output_path(output: Option<&PathBuf>) -> PathBuf {
+    container::trace_path(output.map_or("pfviz".as_ref(), |p| p.as_path()))
+}
+
+fn write_output(perf_data: &PerfData, meta: &CaptureMeta, output: Option<&PathBuf>) -> Result<()> {
+    let path = output_path(output);
+    tracing::info!("writing trace to {}", path.display());
+    container::write_trace(perf_data, meta, File::create(path)?)
+}
+
+/// A file next to the trace for the native backend to write records into as it captures them.
+/// It's unlinked straight away, so that it goes away with the process however that ends.
+fn scratch_file(cli: &TraceCli) -> Result<File> {
+    let path = output_path(cli.output.as_ref()).with_extension("records");
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// A file that's deleted once it's no longer needed, whether or not things went well.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::debug!("failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Capture by running `perf record`, then decoding the perf.data file it writes.
+fn trace_perf(cli: &TraceCli, ev_map: &HashMap<&str, EventKind>) -> Result<PerfData> {
+    // Next to the trace rather than in the current directory, where it could clobber the user's
+    // own perf.data.
+    let perf_data = TempFile(output_path(cli.output.as_ref()).with_extension("perf.data"));
+    let attach = cli.pid.or(cli.tid);
+    let mut command = Command::new("perf");
+    command
+        .arg("record")
+        .arg("-o")
+        .arg(&perf_data.0)
+        .arg(if attach.is_some() { "-Td" } else { "-Tda" })
+        .arg("-c")
+        .arg("1")
+        .arg("--sample-cpu");
+
+    if let Some(pid) = cli.pid {
+        command.arg("-p").arg(pid.to_string());
+    }
+    if let Some(tid) = cli.tid {
+        command.arg("-t").arg(tid.to_string());
+    }
+
+    // Events are limited to user space one by one rather than with --all-user, which would also
+    // exclude the kernel from the tracepoint and so lose every sample of it. perf reports events
+    // by the names they were recorded with, so those are mapped back to the events asked for.
+    let recorded = ev_map
+        .iter()
+        .map(|(event, kind)| (user_only(event), (*event, *kind)))
+        .collect::<HashMap<_, _>>();
+    for event in recorded.keys() {
+        command.arg("-e").arg(event);
+    }
 
     command.arg("-e").arg("major-faults:u");
     command.arg("-e").arg("minor-faults:u");
+    // Tells reads and writes apart, where the tracepoint exists.
+    if crate::native::tracepoint_id(crate::perf_sys::PAGE_FAULT_TRACEPOINT).is_ok() {
+        command
+            .arg("-e")
+            .arg(crate::perf_sys::PAGE_FAULT_TRACEPOINT);
+    }
 
     for arg in &cli.command {
         command.arg(arg);
     }
 
+    // Snapshot the existing mappings before perf starts, since the process may have mapped its
+    // files long before we attached.
+    let initial_maps = match attach {
+        Some(pid) => crate::perf::read_proc_maps(pid)?,
+        None => Vec::new(),
+    };
+
     tracing::debug!("starting perf trace with: `{:?}'", command);
+    if attach.is_some() {
+        tracing::info!("attached, press Ctrl-C to stop tracing");
+    }
 
     let status = command.status()?;
-    if !status.success() {
+    if !status.success() && !was_interrupted(&status) {
         bail!("perf record failed");
     }
 
-    let mut command = Command::new("perf");
-    command
-        .arg("script")
-        .arg("-F")
-        .arg("time,event,addr,sym,ip,cpu,tid")
-        .arg("--show-mmap-events")
-        .arg("--reltime")
-        .arg("--no-demangle")
-        .arg("--ns");
-
-    command.stdout(Stdio::piped());
-
-    tracing::debug!("starting perf script with: `{:?}'", command);
-    let mut child = command.spawn()?;
+    let recorded_map = recorded
+        .iter()
+        .map(|(name, (_, kind))| (name.as_str(), *kind))
+        .collect();
+    let mut data = crate::perf_data::read_perf_data(&perf_data.0, &recorded_map, &initial_maps)?;
+    let renames = recorded
+        .iter()
+        .map(|(name, (event, _))| {
+            (
+                data.strings.get_or_intern(name),
+                data.strings.get_or_intern(event),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    for fault in &mut data.faults {
+        if let Some(name) = renames.get(&fault.event_name) {
+            fault.event_name = *name;
+        }
+    }
+    Ok(data)
+}
 
-    let stdout = child.stdout.take().unwrap();
+/// `event` with perf's `:u` modifier, to count it in user space only as the native backend
+/// does. Events with modifiers of their own are left as given, and so are tracepoints, which
+/// fire in the kernel.
+fn user_only(event: &str) -> String {
+    let modified = match event.contains('/') {
+        // PMU events take their modifiers straight after the closing slash.
+        true => !event.ends_with('/'),
+        // A colon starts either modifiers or a tracepoint's event name.
+        false => event.contains(':'),
+    };
+    match (modified, event.ends_with('/')) {
+        (true, _) => event.to_string(),
+        (false, true) => format!("{}u", event),
+        (false, false) => format!("{}:u", event),
+    }
+}
 
-    let perf_data = crate::perf::parse_perf_data(BufReader::new(stdout), ev_map)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if !child.wait()?.success() {
-        bail!("perf script failed");
+    #[test]
+    fn user_only_events() {
+        assert_eq!(user_only("cache-misses"), "cache-misses:u");
+        assert_eq!(user_only("cache-misses:k"), "cache-misses:k");
+        assert_eq!(user_only("r1a2b:upp"), "r1a2b:upp");
+        assert_eq!(user_only("cpu/event=0x3c/"), "cpu/event=0x3c/u");
+        assert_eq!(user_only("cpu/event=0x3c/k"), "cpu/event=0x3c/k");
+        assert_eq!(
+            user_only("syscalls:sys_enter_read"),
+            "syscalls:sys_enter_read"
+        );
     }
-
-    let out_file = File::create(
-        cli.output
-            .clone()
-            .unwrap_or_else(|| PathBuf::from("pfviz.dat")),
-    )?;
-    let out_file_json = File::create(
-        cli.output
-            .clone()
-            .unwrap_or_else(|| PathBuf::from("pfviz.json")),
-    )?;
-    crate::perf::write_perf_data(
-        &perf_data,
-        BufWriter::new(out_file),
-        BufWriter::new(out_file_json),
-    )?;
-
-    Ok(())
 }
@@ -1,7 +1,8 @@
 use std::{
-    collections::HashMap,
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
     path::Path,
-    sync::{Arc, Mutex},
     time::Duration,
 };
 
@@ -9,29 +10,42 @@ use ratatui::{
     buffer::Buffer,
     layout::{Constraint, Direction, Flex, Layout, Rect},
     style::{Color, Style, Stylize},
+    text::{Line, Span},
     widgets::{Block, Borders, Paragraph, Sparkline, SparklineBar, Widget},
 };
 
 use crate::{
-    PlayCli,
+    BarMode, ColorMode, GroupBy, PlayCli, SortKey,
     app::App,
+    breakpoint::Breakpoints,
+    event_log::EventLog,
+    keyframe::DrawSettings,
+    page_grid::{GridScale, PageCell, PageGrid},
     perf::{EventKind, EventRecord, FaultData, PAGE_SIZE},
-    single_file_ui::SingleFileVis,
+    single_file_ui::{FileComponent, SingleFileVis},
+    timeline::{Timeline, TimelineMarks},
 };
 
 #[derive(Debug)]
 pub struct Ui {
     pub fault_vis: FaultVis,
     pub status: Status,
+    pub timeline: Timeline,
+    pub event_log: EventLog,
+    pub breakpoints: Breakpoints,
     pub map: HashMap<usize, usize>,
 }
 
 impl Ui {
     pub fn new(cli: &PlayCli, data: &FaultData) -> Self {
         let mut map = HashMap::new();
+        let status = Status::new(cli, data);
         Self {
             fault_vis: FaultVis::new(cli, data, &mut map),
-            status: Status::new(cli, data),
+            timeline: Timeline::new(data, status.end_time),
+            event_log: EventLog::new(cli.event_log),
+            status,
+            breakpoints: Breakpoints::default(),
             map,
         }
     }
@@ -40,6 +54,20 @@ impl Ui {
         self.status.reset();
         self.fault_vis.reset();
     }
+
+    pub fn draw_settings(&self) -> DrawSettings {
+        DrawSettings {
+            color_mode: self.status.color_mode,
+            picked_threads: self.status.threads.picked.clone(),
+            hide_threads: self.status.threads.hide,
+            single_file: self
+                .fault_vis
+                .single_file
+                .as_ref()
+                .map(|sf| (sf.obj_id(), sf.range())),
+            page_grid: self.fault_vis.page_grid.as_ref().map(PageGrid::view),
+        }
+    }
 }
 
 impl Widget for &App {
@@ -52,12 +80,75 @@ impl Widget for &App {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let layout = Layout::new(
             Direction::Vertical,
-            &[Constraint::Fill(1), Constraint::Length(8)],
+            [
+                Constraint::Fill(1),
+                Constraint::Length(7),
+                Constraint::Length(8),
+            ],
         );
         let split = layout.split(area);
 
-        self.ui.fault_vis.render(split[0], buf);
-        self.ui.status.render(split[1], buf);
+        let files = if self.ui.event_log.visible {
+            let [files, log] =
+                Layout::vertical([Constraint::Fill(1), Constraint::Length(14)]).areas(split[0]);
+            self.ui
+                .event_log
+                .render(log, buf, &self.data, self.ui.status.cur_event);
+            files
+        } else {
+            split[0]
+        };
+        if self.ui.breakpoints.list.is_empty() {
+            self.ui
+                .fault_vis
+                .render_at(files, buf, self.ui.status.cur_time);
+        } else {
+            let hsplit = Layout::new(
+                Direction::Horizontal,
+                [Constraint::Fill(1), Constraint::Length(50)],
+            )
+            .split(files);
+            self.ui
+                .fault_vis
+                .render_at(hsplit[0], buf, self.ui.status.cur_time);
+            self.ui.breakpoints.render(hsplit[1], buf);
+        }
+        let status = &self.ui.status;
+        let marker_time = |event: Option<usize>| {
+            let faults = self.data.records.slice();
+            event.and_then(|e| {
+                faults
+                    .get(e.min(faults.len().saturating_sub(1)))
+                    .map(|f| f.time())
+            })
+        };
+        let marks = TimelineMarks {
+            now: status.cur_time,
+            marker_a: marker_time(status.marker_a),
+            marker_b: marker_time(status.marker_b),
+        };
+        self.ui.timeline.render(split[1], buf, marks);
+        status.render(split[2], buf);
+    }
+}
+
+impl Widget for &Breakpoints {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let lines = self
+            .list
+            .iter()
+            .enumerate()
+            .map(|(i, bp)| Line::from(format!("{}: {}", i + 1, bp)))
+            .collect::<Vec<_>>();
+        Paragraph::new(lines)
+            .wrap(ratatui::widgets::Wrap { trim: false })
+            .block(
+                Block::new()
+                    .borders(Borders::ALL)
+                    .title("Breakpoints")
+                    .title_bottom(":break <cond>; :watch FILE OFFSET; :delete N|all"),
+            )
+            .render(area, buf);
     }
 }
 
@@ -93,14 +184,164 @@ pub struct FileVis {
     bar_size: u64,
     faults: usize,
     misses: usize,
+    /// Events so far in each bar, for the heatmap.
+    fault_counts: Vec<u64>,
+    miss_counts: Vec<u64>,
     is_highlighted: bool,
     breakpoint: bool,
     pub objid: usize,
+    /// Full path of the file, for grouping.
+    pub path: String,
+    /// Length of the file's mapping, for sorting.
+    pub size: u64,
+    /// Events on the file over the whole trace, for sorting.
+    pub totals: FileTotals,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileTotals {
+    pub faults: u64,
+    pub misses: u64,
+    pub major: u64,
+}
+
+/// The parts of a [`FileVis`] that playing events changes.
+#[derive(Clone, Debug)]
+pub struct FileVisState {
+    faultdata: Vec<RegionInfo>,
+    cachedata: Vec<RegionInfo>,
+    faults: usize,
+    misses: usize,
+    fault_counts: Vec<u64>,
+    miss_counts: Vec<u64>,
+}
+
+/// The parts of a [`FaultVis`] that playing events changes.
+#[derive(Clone, Debug, Default)]
+pub struct FaultVisState {
+    files: Vec<FileVisState>,
+    single_file: Option<Vec<FileComponent>>,
+    page_grid: Option<Vec<PageCell>>,
+}
+
+/// Colours (foreground, background) to draw a fault with. By kind, misses are green, major faults
+/// red (magenta while a major fault is recent), write faults yellow and other faults blue.
+pub fn fault_colors(
+    fault: &EventRecord,
+    has_recent_major: bool,
+    mode: ColorMode,
+) -> (Color, Color) {
+    match mode {
+        ColorMode::Kind => {}
+        ColorMode::Cpu => return palette_colors(fault.cpu()),
+        ColorMode::Thread => return palette_colors(fault.tid()),
+    }
+    if fault.kind().is_miss() {
+        (Color::LightGreen, Color::Green)
+    } else if fault.kind() == EventKind::MajorFault {
+        (Color::LightRed, Color::Red)
+    } else if has_recent_major {
+        (Color::LightMagenta, Color::Magenta)
+    } else if fault.was_write() == Some(true) {
+        (Color::LightYellow, Color::Yellow)
+    } else {
+        (Color::LightBlue, Color::Blue)
+    }
+}
+
+const PALETTE: [(Color, Color); 7] = [
+    (Color::LightBlue, Color::Blue),
+    (Color::LightGreen, Color::Green),
+    (Color::LightYellow, Color::Yellow),
+    (Color::LightMagenta, Color::Magenta),
+    (Color::LightCyan, Color::Cyan),
+    (Color::LightRed, Color::Red),
+    (Color::White, Color::Gray),
+];
+
+/// Colours for events from threads that weren't picked.
+pub const DIM_COLORS: (Color, Color) = (Color::DarkGray, Color::Black);
+
+/// Colours for a CPU or thread.
+pub fn palette_colors(n: u32) -> (Color, Color) {
+    PALETTE[n as usize % PALETTE.len()]
+}
+
+/// Labelled colours explaining a colour mode.
+fn legend(mode: ColorMode, cpus: &[u32], tids: &[u32]) -> Vec<(String, Color)> {
+    match mode {
+        ColorMode::Kind => [
+            ("miss", Color::Green),
+            ("major", Color::Red),
+            ("after major", Color::Magenta),
+            ("write", Color::Yellow),
+            ("read/other", Color::Blue),
+        ]
+        .into_iter()
+        .map(|(label, color)| (label.to_string(), color))
+        .collect(),
+        ColorMode::Cpu => cpus
+            .iter()
+            .map(|cpu| (format!("cpu {}", cpu), palette_colors(*cpu).1))
+            .collect(),
+        ColorMode::Thread => tids
+            .iter()
+            .map(|tid| (format!("tid {}", tid), palette_colors(*tid).1))
+            .collect(),
+    }
+}
+
+/// Threads picked out during playback. Events from the other threads are dimmed, or hidden.
+#[derive(Debug)]
+pub struct ThreadFilter {
+    /// Threads that appear in the trace.
+    pub tids: Vec<u32>,
+    /// Index into `tids` of the thread that (T) picks.
+    pub cursor: Option<usize>,
+    /// Picked threads; if empty, every thread is shown normally.
+    pub picked: BTreeSet<u32>,
+    pub hide: bool,
 }
 
-impl Into<SparklineBar> for &RegionInfo {
-    fn into(self) -> SparklineBar {
-        SparklineBar::from(self.value).style(self.style)
+impl ThreadFilter {
+    pub fn new(cli: &PlayCli, data: &FaultData) -> Self {
+        let mut tids = data
+            .records
+            .slice()
+            .iter()
+            .map(|f| f.tid())
+            .collect::<Vec<_>>();
+        tids.sort_unstable();
+        tids.dedup();
+        Self {
+            tids,
+            cursor: None,
+            picked: cli.tid.iter().copied().collect(),
+            hide: cli.hide_others,
+        }
+    }
+
+    /// Move the cursor to the next thread, or off the end of the list.
+    pub fn next(&mut self) {
+        self.cursor = match self.cursor {
+            None if !self.tids.is_empty() => Some(0),
+            Some(i) if i + 1 < self.tids.len() => Some(i + 1),
+            _ => None,
+        };
+    }
+
+    /// Pick or unpick the thread under the cursor.
+    pub fn toggle(&mut self) {
+        let Some(tid) = self.cursor.map(|i| self.tids[i]) else {
+            return;
+        };
+        if !self.picked.remove(&tid) {
+            self.picked.insert(tid);
+        }
+    }
+
+    pub fn is_picked(&self, tid: u32) -> bool {
+        self.picked.is_empty() || self.picked.contains(&tid)
     }
 }
 
@@ -112,6 +353,39 @@ pub struct FaultProcessResult {
 
 pub const CACHE_SET: u64 = 10000;
 pub const CACHE_MAX: u64 = 9000;
+/// Height of a fresh highlight in the overview, which has one row per bar.
+const BAR_MAX: u64 = 8;
+
+/// Height, out of `max`, of a highlight `age` old that halves every `half_life`.
+pub fn decay(max: u64, age: Duration, half_life: Duration) -> u64 {
+    if half_life.is_zero() {
+        return if age.is_zero() { max } else { 0 };
+    }
+    (max as f64 * 0.5f64.powf(age.as_secs_f64() / half_life.as_secs_f64())).round() as u64
+}
+
+/// Whether a mapping is of a shared library, a data file or anonymous memory, by its path.
+fn file_type(path: &str) -> &'static str {
+    const ANON: [&str; 6] = [
+        "[",
+        "//anon",
+        "/memfd:",
+        "anon_inode:",
+        "/dev/zero",
+        "/SYSV",
+    ];
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    if path.is_empty() || ANON.iter().any(|prefix| path.starts_with(prefix)) {
+        "anonymous"
+    } else if name.ends_with(".so") || name.contains(".so.") {
+        "shared libraries"
+    } else {
+        "data files"
+    }
+}
 
 impl FileVis {
     pub fn new(name: String, start_off: u64, end_off: u64, bar_size: u64, objid: usize) -> Self {
@@ -122,6 +396,8 @@ impl FileVis {
         ];
         Self {
             faults: 0,
+            fault_counts: vec![0; data.len()],
+            miss_counts: vec![0; data.len()],
             faultdata: data.clone(),
             cachedata: data,
             name: Path::new(&name)
@@ -136,6 +412,43 @@ impl FileVis {
             is_highlighted: false,
             breakpoint: false,
             objid,
+            path: String::new(),
+            size: end_off - start_off,
+            totals: FileTotals::default(),
+        }
+    }
+
+    /// The group the file is shown in.
+    fn group(&self, group: GroupBy) -> String {
+        match group {
+            GroupBy::None => String::new(),
+            GroupBy::Dir => match Path::new(&self.path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+                _ => file_type(&self.path).to_string(),
+            },
+            GroupBy::Type => file_type(&self.path).to_string(),
+        }
+    }
+
+    /// When the file was last faulted on, as of the events played so far.
+    fn last_active(&self) -> Option<Duration> {
+        self.faultdata
+            .iter()
+            .chain(&self.cachedata)
+            .filter(|region| region.value.is_some())
+            .map(|region| region.time)
+            .max()
+    }
+
+    /// What the files are sorted by, biggest first.
+    fn sort_value(&self, sort: SortKey) -> u128 {
+        match sort {
+            SortKey::Name => 0,
+            SortKey::Faults => self.totals.faults.into(),
+            SortKey::Misses => self.totals.misses.into(),
+            SortKey::Major => self.totals.major.into(),
+            SortKey::Size => self.size.into(),
+            SortKey::Recent => self.last_active().map_or(0, |time| time.as_nanos() + 1),
         }
     }
 
@@ -145,6 +458,8 @@ impl FileVis {
             RegionInfo::new(0, Duration::ZERO, Style::default().bg(Color::DarkGray));
             len.try_into().unwrap()
         ];
+        self.fault_counts = vec![0; data.len()];
+        self.miss_counts = vec![0; data.len()];
         self.faultdata = data.clone();
         self.cachedata = data.clone();
         self.misses = 0;
@@ -155,7 +470,33 @@ impl FileVis {
         self.breakpoint = !self.breakpoint;
     }
 
-    pub fn fault(&mut self, faults: &[EventRecord], _fd: &FaultData) -> FaultProcessResult {
+    fn snapshot(&self) -> FileVisState {
+        FileVisState {
+            faultdata: self.faultdata.clone(),
+            cachedata: self.cachedata.clone(),
+            faults: self.faults,
+            misses: self.misses,
+            fault_counts: self.fault_counts.clone(),
+            miss_counts: self.miss_counts.clone(),
+        }
+    }
+
+    fn restore(&mut self, state: &FileVisState) {
+        self.faultdata.clone_from(&state.faultdata);
+        self.cachedata.clone_from(&state.cachedata);
+        self.faults = state.faults;
+        self.misses = state.misses;
+        self.fault_counts.clone_from(&state.fault_counts);
+        self.miss_counts.clone_from(&state.miss_counts);
+    }
+
+    pub fn fault(
+        &mut self,
+        faults: &[EventRecord],
+        _fd: &FaultData,
+        mode: ColorMode,
+        dim: bool,
+    ) -> FaultProcessResult {
         for (idx, fault) in faults.iter().enumerate() {
             let pos = ((fault.offset() - self.start_off) / self.bar_size) as usize;
             let region_vec = if fault.kind().is_miss() {
@@ -168,6 +509,11 @@ impl FileVis {
             if pos >= region_vec.len() {
                 continue;
             }
+            if fault.kind().is_miss() {
+                self.miss_counts[pos] += 1;
+            } else {
+                self.fault_counts[pos] += 1;
+            }
 
             let mut has_recent_major = if let Some(count) = &mut region_vec[pos].has_major {
                 if *count == 0 {
@@ -189,39 +535,19 @@ impl FileVis {
                 region_vec[pos].has_major = None;
             }
 
-            let mut colors = if fault.kind() == EventKind::MajorFault {
-                (Color::LightRed, Color::Red)
-            } else if has_recent_major {
-                (Color::LightMagenta, Color::Magenta)
+            let colors = if dim {
+                DIM_COLORS
             } else {
-                (Color::LightBlue, Color::Blue)
+                fault_colors(fault, has_recent_major, mode)
             };
 
-            if fault.kind().is_miss() {
-                colors = (Color::LightGreen, Color::Green);
-            }
-
-            region_vec[pos as usize] = RegionInfo::new(
+            region_vec[pos] = RegionInfo::new(
                 fault.offset(),
                 fault.time(),
                 Style::default().fg(colors.0).bg(colors.1),
             );
-            for i in 0..region_vec.len() {
-                if i != pos as usize {
-                    if fault.kind().is_miss() {
-                        if region_vec[i].value == Some(0) {
-                            region_vec[i].value = None;
-                        }
-                    }
-                    if let Some(ref mut val) = region_vec[i].value {
-                        if *val > 0 {
-                            *val -= 1;
-                        }
-                    }
-                }
-            }
-            region_vec[pos as usize].value =
-                Some(if fault.kind().is_miss() { CACHE_SET } else { 1 });
+            // Only marks the bar as touched; how far it has faded is worked out when drawing.
+            region_vec[pos].value = Some(BAR_MAX);
 
             if self.breakpoint {
                 return FaultProcessResult {
@@ -237,11 +563,80 @@ impl FileVis {
     }
 }
 
-impl Widget for &FileVis {
-    fn render(self, area: Rect, buf: &mut Buffer)
-    where
-        Self: Sized,
-    {
+/// Colours of the heatmap, from fewest to most events.
+const HEAT: [Color; 6] = [
+    Color::Blue,
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::LightRed,
+    Color::Red,
+];
+
+/// How a file's bars are drawn.
+#[derive(Clone, Copy, Debug)]
+enum BarScale {
+    /// The latest events, faded by how long ago they were at `now`.
+    Recent {
+        now: Duration,
+        half_life: Duration,
+    },
+    Heat(HeatScale),
+}
+
+/// What the heatmap's colours are scaled against.
+#[derive(Clone, Copy, Debug)]
+struct HeatScale {
+    max_faults: u64,
+    max_misses: u64,
+    log: bool,
+}
+
+/// How far `count` is towards `max`, from 0 to 1.
+fn heat_fraction(count: u64, max: u64, log: bool) -> f64 {
+    if log {
+        (count as f64).ln_1p() / (max as f64).ln_1p()
+    } else {
+        count as f64 / max as f64
+    }
+}
+
+/// The heatmap colour for `count` events, out of at most `max`.
+pub fn heat_color(count: u64, max: u64, log: bool) -> Color {
+    let frac = heat_fraction(count, max, log);
+    HEAT[((frac * HEAT.len() as f64) as usize).min(HEAT.len() - 1)]
+}
+
+impl HeatScale {
+    fn bar(&self, count: u64, max: u64) -> SparklineBar {
+        if count == 0 {
+            return SparklineBar::from(None).style(Style::default().bg(Color::DarkGray));
+        }
+        let frac = heat_fraction(count, max, self.log);
+        SparklineBar::from(Some((frac * 8.0).ceil().max(1.0) as u64)).style(
+            Style::default()
+                .fg(heat_color(count, max, self.log))
+                .bg(Color::Black),
+        )
+    }
+
+    fn legend(&self) -> Line<'static> {
+        let mut spans = vec![Span::raw(if self.log {
+            "heatmap (log): "
+        } else {
+            "heatmap: "
+        })];
+        spans.extend(HEAT.map(|color| Span::styled("█", Style::default().fg(color))));
+        spans.push(Span::raw(format!(
+            " up to {} faults and {} misses per bar",
+            self.max_faults, self.max_misses
+        )));
+        Line::from(spans)
+    }
+}
+
+impl FileVis {
+    fn render_bars(&self, area: Rect, buf: &mut Buffer, scale: BarScale, group: Option<&str>) {
         let start = humansize::format_size(self.start_off, humansize::BINARY);
         let end = humansize::format_size(self.end_off, humansize::BINARY);
         let bs = humansize::format_size(self.bar_size, humansize::BINARY);
@@ -255,7 +650,7 @@ impl Widget for &FileVis {
         } else {
             &self.name
         };
-        let block = Block::new()
+        let mut block = Block::new()
             .title(title.as_str())
             .title_style(style)
             .borders(Borders::ALL)
@@ -268,16 +663,49 @@ impl Widget for &FileVis {
                 self.faults,
                 self.misses
             ));
+        if let Some(group) = group {
+            block = block.title(Line::from(group.to_string()).right_aligned().italic());
+        }
 
         let inner = block.inner(area);
         let inner_layout = Layout::new(
             Direction::Vertical,
-            &[Constraint::Length(1), Constraint::Length(1)],
+            [Constraint::Length(1), Constraint::Length(1)],
         );
         let splits = inner_layout.split(inner);
 
-        let fault_sparkline = Sparkline::default().max(1).data(&self.faultdata);
-        let cache_sparkline = Sparkline::default().max(CACHE_MAX).data(&self.cachedata);
+        let (cache_sparkline, fault_sparkline) = match scale {
+            BarScale::Recent { now, half_life } => {
+                let bar = |region: &RegionInfo, miss: bool| {
+                    let value = region
+                        .value
+                        .map(|_| decay(BAR_MAX, now.saturating_sub(region.time), half_life))
+                        // Faded misses disappear, while faults keep their colour.
+                        .filter(|value| !miss || *value > 0);
+                    SparklineBar::from(value).style(region.style)
+                };
+                (
+                    Sparkline::default()
+                        .max(BAR_MAX)
+                        .data(self.cachedata.iter().map(|region| bar(region, true))),
+                    Sparkline::default()
+                        .max(BAR_MAX)
+                        .data(self.faultdata.iter().map(|region| bar(region, false))),
+                )
+            }
+            BarScale::Heat(heat) => (
+                Sparkline::default().max(8).data(
+                    self.miss_counts
+                        .iter()
+                        .map(|count| heat.bar(*count, heat.max_misses)),
+                ),
+                Sparkline::default().max(8).data(
+                    self.fault_counts
+                        .iter()
+                        .map(|count| heat.bar(*count, heat.max_faults)),
+                ),
+            ),
+        };
         block.render(area, buf);
         cache_sparkline.render(splits[0], buf);
         fault_sparkline.render(splits[1], buf);
@@ -287,9 +715,22 @@ impl Widget for &FileVis {
 #[derive(Debug)]
 pub struct FaultVis {
     file_vis: Vec<FileVis>,
-    single_file: Option<SingleFileVis>,
+    pub single_file: Option<SingleFileVis>,
+    pub page_grid: Option<PageGrid>,
     width: u16,
     highlighted: Option<usize>,
+    /// Only show events from these CPUs, or all if empty.
+    cpus: Vec<u32>,
+    pub bar_mode: BarMode,
+    pub half_life: Duration,
+    pub sort: SortKey,
+    pub group: GroupBy,
+    /// First row of files on screen, moved along to keep the highlighted file in view.
+    scroll: Cell<usize>,
+    /// How many files fit on screen, as of the last draw.
+    page_len: Cell<usize>,
+    /// Where the files were drawn last.
+    area: Cell<Rect>,
 }
 
 impl FaultVis {
@@ -314,13 +755,45 @@ impl FaultVis {
                 name = "...".to_string() + &name[cut..name.len()];
             }
             map.insert(object.idx, file_vis.len());
-            file_vis.push(FileVis::new(name, start, end, bar_size, object.idx));
+            let mut fv = FileVis::new(name, start, end, bar_size, object.idx);
+            fv.path = data
+                .json
+                .strings
+                .resolve(object.file)
+                .unwrap_or_default()
+                .to_string();
+            if object.size > 0 {
+                fv.size = object.size;
+            }
+            file_vis.push(fv);
+        }
+        for fault in data.records.slice() {
+            let Some(fv) = map.get(&fault.obj_id()).map(|idx| &mut file_vis[*idx]) else {
+                continue;
+            };
+            match fault.kind() {
+                kind if kind.is_miss() => fv.totals.misses += 1,
+                EventKind::MajorFault => {
+                    fv.totals.faults += 1;
+                    fv.totals.major += 1;
+                }
+                _ => fv.totals.faults += 1,
+            }
         }
         Self {
             file_vis,
             single_file: None,
+            page_grid: None,
             width: cli.width as u16,
             highlighted: None,
+            cpus: cli.cpu.clone(),
+            bar_mode: cli.bar_mode,
+            half_life: Duration::from_secs_f64(cli.half_life.max(0.0)),
+            sort: cli.sort,
+            group: cli.group,
+            scroll: Cell::new(0),
+            page_len: Cell::new(1),
+            area: Cell::new(Rect::default()),
         }
     }
 
@@ -331,6 +804,57 @@ impl FaultVis {
         if let Some(sf) = self.single_file.as_mut() {
             sf.reset();
         }
+        if let Some(grid) = self.page_grid.as_mut() {
+            grid.reset();
+        }
+    }
+
+    pub fn snapshot(&self) -> FaultVisState {
+        FaultVisState {
+            files: self.file_vis.iter().map(FileVis::snapshot).collect(),
+            single_file: self.single_file.as_ref().map(SingleFileVis::snapshot),
+            page_grid: self.page_grid.as_ref().map(PageGrid::snapshot),
+        }
+    }
+
+    pub fn restore(&mut self, state: &FaultVisState) {
+        for (fv, state) in self.file_vis.iter_mut().zip(&state.files) {
+            fv.restore(state);
+        }
+        if let (Some(sf), Some(state)) = (self.single_file.as_mut(), &state.single_file) {
+            sf.restore(state);
+        }
+        if let (Some(grid), Some(state)) = (self.page_grid.as_mut(), &state.page_grid) {
+            grid.restore(state);
+        }
+    }
+
+    fn heat_scale(&self) -> HeatScale {
+        let max = |counts: fn(&FileVis) -> &Vec<u64>| {
+            self.file_vis
+                .iter()
+                .flat_map(|fv| counts(fv).iter().copied())
+                .max()
+                .unwrap_or(0)
+        };
+        HeatScale {
+            max_faults: max(|fv| &fv.fault_counts),
+            max_misses: max(|fv| &fv.miss_counts),
+            log: self.bar_mode == BarMode::HeatLog,
+        }
+    }
+
+    /// Rough size of a snapshot of the files' bars, in bytes.
+    pub fn state_size(&self) -> usize {
+        self.file_vis
+            .iter()
+            .map(|fv| (fv.faultdata.len() + fv.cachedata.len()) * size_of::<RegionInfo>())
+            .sum::<usize>()
+            + self
+                .single_file
+                .as_ref()
+                .map_or(0, SingleFileVis::state_size)
+            + self.page_grid.as_ref().map_or(0, PageGrid::state_size)
     }
 
     pub fn fault(
@@ -338,17 +862,34 @@ impl FaultVis {
         faults: &[EventRecord],
         data: &FaultData,
         map: &HashMap<usize, usize>,
+        status: &Status,
     ) -> FaultProcessResult {
+        let threads = &status.threads;
         let mut count = 0;
         for fault in faults {
             let Some(idx) = map.get(&fault.obj_id()) else {
+                count += 1;
                 continue;
             };
-            let res = self.file_vis[*idx].fault(&[*fault], data);
-            if let Some(single_file) = self.single_file.as_mut() {
-                if single_file.obj_id() == fault.obj_id() {
-                    single_file.fault(&[*fault], data);
-                }
+            if !self.cpus.is_empty() && !self.cpus.contains(&fault.cpu()) {
+                count += 1;
+                continue;
+            }
+            let dim = !threads.is_picked(fault.tid());
+            if dim && threads.hide {
+                count += 1;
+                continue;
+            }
+            let res = self.file_vis[*idx].fault(&[*fault], data, status.color_mode, dim);
+            if let Some(single_file) = self.single_file.as_mut()
+                && single_file.obj_id() == fault.obj_id()
+            {
+                single_file.fault(&[*fault], data, status.color_mode, dim);
+            }
+            if let Some(grid) = self.page_grid.as_mut()
+                && grid.obj_id() == fault.obj_id()
+            {
+                grid.fault(&[*fault], status.color_mode, dim);
             }
             if res.hit_breakpoint {
                 return FaultProcessResult {
@@ -360,7 +901,7 @@ impl FaultVis {
         }
 
         if let Some(single_file) = self.single_file.as_mut() {
-            single_file.calculate_decay(faults.last().unwrap().time());
+            single_file.calculate_decay(faults.last().unwrap().time(), self.half_life);
         }
 
         FaultProcessResult {
@@ -378,55 +919,215 @@ impl FaultVis {
     }
 
     pub fn move_highlight(&mut self, up: bool) {
-        if let Some(old) = self.highlighted {
-            self.file_vis[old].is_highlighted = false;
+        if let Some(single_file) = self.single_file.as_mut() {
+            single_file.move_cursor(up);
+            return;
         }
-        let value = self
+        self.move_highlight_by(1, up);
+    }
+
+    /// Move the highlight by a screenful of files.
+    pub fn page_highlight(&mut self, up: bool) {
+        if self.single_file.is_some() {
+            return;
+        }
+        self.move_highlight_by(self.page_len.get(), up);
+    }
+
+    fn move_highlight_by(&mut self, step: usize, up: bool) {
+        if self.file_vis.is_empty() {
+            return;
+        }
+        let order = self.display_order();
+        let pos = self
             .highlighted
+            .and_then(|old| order.iter().position(|(_, idx)| *idx == old))
             .map(|old| {
                 if up {
-                    old.saturating_sub(1)
+                    old.saturating_sub(step)
                 } else {
-                    old.saturating_add(1).min(self.file_vis.len() - 1)
+                    old.saturating_add(step).min(order.len() - 1)
                 }
             })
             .unwrap_or(0);
+        if let Some(old) = self.highlighted {
+            self.file_vis[old].is_highlighted = false;
+        }
+        let value = order[pos].1;
         self.highlighted = Some(value);
         self.file_vis[value].is_highlighted = true;
     }
 
-    pub fn select(&mut self) {
-        if self.single_file.is_some() {
-            return;
+    /// The files in the order they're shown, with the group each is in. Ties are broken by name
+    /// and path, so the order is the same from run to run.
+    fn display_order(&self) -> Vec<(String, usize)> {
+        let mut order = (0..self.file_vis.len())
+            .map(|idx| (self.file_vis[idx].group(self.group), idx))
+            .collect::<Vec<_>>();
+        order.sort_by_cached_key(|(group, idx)| {
+            let fv = &self.file_vis[*idx];
+            (
+                group.clone(),
+                Reverse(fv.sort_value(self.sort)),
+                fv.name.clone(),
+                fv.path.clone(),
+            )
+        });
+        order
+    }
+
+    /// Open the highlighted file on its own, or zoom in further if it's already open. Returns
+    /// whether the view changed.
+    pub fn select(&mut self) -> bool {
+        if let Some(single_file) = self.single_file.as_mut() {
+            return single_file.zoom_in();
+        }
+        let Some(selected) = self.highlighted else {
+            return false;
+        };
+        self.single_file = Some(SingleFileVis::new(&self.file_vis[selected]));
+        true
+    }
+
+    /// Open the pages of the highlighted file, or of the range looked at in the single-file view,
+    /// as a grid filling the screen. Closes the grid if it's open. Returns whether the events
+    /// played so far need replaying into the grid.
+    pub fn toggle_page_grid(&mut self) -> bool {
+        if self.page_grid.take().is_some() {
+            return false;
         }
-        if let Some(selected) = self.highlighted {
-            self.single_file = Some(SingleFileVis::new(&self.file_vis[selected]));
+        let (objid, name, range) = match (&self.single_file, self.highlighted) {
+            (Some(sf), _) => (sf.obj_id(), sf.name().to_string(), sf.range()),
+            (None, Some(idx)) => {
+                let fv = &self.file_vis[idx];
+                (fv.objid, fv.name.clone(), fv.start_off..fv.end_off)
+            }
+            (None, None) => return false,
+        };
+        if range.is_empty() {
+            return false;
         }
+        // Every row but the header, with two pages to a character cell.
+        let area = self.area.get();
+        let capacity = usize::from(area.width) * usize::from(area.height.saturating_sub(1)) * 2;
+        self.page_grid = Some(PageGrid::new(objid, name, range, capacity));
+        true
     }
 
+    /// Zoom out of the file that's open on its own, or go back to all the files. Returns false if
+    /// all the files were already shown.
     pub fn deselect(&mut self) -> bool {
+        if let Some(single_file) = self.single_file.as_mut()
+            && single_file.zoom_out()
+        {
+            return true;
+        }
         self.single_file.take().is_some()
     }
 }
 
-impl Widget for &FaultVis {
-    fn render(self, area: Rect, buf: &mut Buffer)
-    where
-        Self: Sized,
-    {
+impl FaultVis {
+    /// Draw the files as of trace time `now`.
+    pub fn render_at(&self, area: Rect, buf: &mut Buffer, now: Duration) {
+        self.area.set(area);
+        if let Some(grid) = self.page_grid.as_ref() {
+            let scale = match self.bar_mode {
+                BarMode::Recent => GridScale::Recent {
+                    now,
+                    half_life: self.half_life,
+                },
+                BarMode::Heat => GridScale::Heat { log: false },
+                BarMode::HeatLog => GridScale::Heat { log: true },
+            };
+            grid.render(area, buf, scale);
+            return;
+        }
         if let Some(single_file) = self.single_file.as_ref() {
             single_file.render(area, buf);
             return;
         }
-        const MAX_H: usize = 32;
-        const MAX_V: usize = 32;
-        let hcount: usize = usize::try_from(area.as_size().width / (self.width + 4))
-            .unwrap()
-            .min(MAX_H);
-        let vcount = (self.file_vis.len() / hcount + 1).min(MAX_V);
+        let scale = match self.bar_mode {
+            BarMode::Recent => BarScale::Recent {
+                now,
+                half_life: self.half_life,
+            },
+            BarMode::Heat | BarMode::HeatLog => BarScale::Heat(self.heat_scale()),
+        };
+        let area = match scale {
+            BarScale::Recent { .. } => area,
+            BarScale::Heat(heat) => {
+                let [legend, rest] =
+                    Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(area);
+                heat.legend().render(legend, buf);
+                rest
+            }
+        };
+        const ROW_HEIGHT: u16 = 4;
+        let hcount = usize::from(area.width / (self.width + 4)).max(1);
+        // Each group starts a new row, with its first file labelled with the group.
+        let mut grid: Vec<Vec<(usize, Option<String>)>> = Vec::new();
+        let mut last_group = None;
+        for (group, idx) in self.display_order() {
+            let label = (self.group != GroupBy::None && last_group.as_ref() != Some(&group))
+                .then(|| group.clone());
+            match grid.last_mut() {
+                Some(row) if label.is_none() && row.len() < hcount => row.push((idx, label)),
+                _ => grid.push(vec![(idx, label)]),
+            }
+            last_group = Some(group);
+        }
+        let rows = grid.len();
+        let mut vcount = usize::from(area.height / ROW_HEIGHT).max(1);
+        // When the files don't all fit, a line above and below says how many rows are hidden.
+        let (above, area, below) = if rows > vcount {
+            vcount = usize::from(area.height.saturating_sub(2) / ROW_HEIGHT).max(1);
+            let [above, area, below] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ])
+            .areas(area);
+            (Some(above), area, Some(below))
+        } else {
+            (None, area, None)
+        };
+        let vcount = vcount.min(rows);
+        self.page_len.set(vcount * hcount);
+
+        let mut scroll = self.scroll.get();
+        if let Some(row) = self.highlighted.and_then(|h| {
+            grid.iter()
+                .position(|row| row.iter().any(|(idx, _)| *idx == h))
+        }) {
+            if row < scroll {
+                scroll = row;
+            } else if row >= scroll + vcount {
+                scroll = row + 1 - vcount;
+            }
+        }
+        let scroll = scroll.min(rows - vcount);
+        self.scroll.set(scroll);
+        if let Some(above) = above {
+            let text = if scroll > 0 {
+                format!("▲ {} more rows (PgUp)", scroll)
+            } else {
+                String::new()
+            };
+            Line::from(text).centered().render(above, buf);
+        }
+        if let Some(below) = below {
+            let hidden = rows - scroll - vcount;
+            let text = if hidden > 0 {
+                format!("▼ {} more rows (PgDn)", hidden)
+            } else {
+                String::new()
+            };
+            Line::from(text).centered().render(below, buf);
+        }
+
         let layout = Layout::new(
             Direction::Vertical,
-            Constraint::from_lengths(vec![4u16; vcount]),
+            Constraint::from_lengths(vec![ROW_HEIGHT; vcount]),
         )
         .flex(Flex::SpaceAround);
         let splits = layout.split(area);
@@ -438,14 +1139,14 @@ impl Widget for &FaultVis {
         .flex(Flex::SpaceAround);
 
         let allsplits = splits
-            .into_iter()
+            .iter()
             .map(|vs| hlayout.split(*vs))
             .collect::<Vec<_>>();
 
-        for (idx, fv) in self.file_vis.iter().enumerate() {
-            let area = &allsplits[idx / hcount];
-            let area = area[idx % hcount];
-            fv.render(area, buf);
+        for (row, areas) in grid.iter().skip(scroll).zip(&allsplits) {
+            for ((idx, label), area) in row.iter().zip(areas.iter()) {
+                self.file_vis[*idx].render_bars(*area, buf, scale, label.as_deref());
+            }
         }
     }
 }
@@ -462,6 +1163,16 @@ pub struct Status {
     pub marker_b: Option<usize>,
     pub looping: bool,
     pub paused: bool,
+    /// Play backwards.
+    pub reverse: bool,
+    /// Input to the `:` prompt, while it's open.
+    pub prompt: Option<String>,
+    /// How much of the trace has been indexed for seeking, while that's still going on.
+    pub indexed: Option<f64>,
+    pub color_mode: ColorMode,
+    pub threads: ThreadFilter,
+    /// CPUs that appear in the trace, for the legend.
+    cpus: Vec<u32>,
 }
 
 impl Status {
@@ -472,6 +1183,14 @@ impl Status {
             .iter()
             .max_by(|a, b| a.time().cmp(&b.time()))
             .map_or(Duration::ZERO, |f| f.time());
+        let mut cpus = data
+            .records
+            .slice()
+            .iter()
+            .map(|f| f.cpu())
+            .collect::<Vec<_>>();
+        cpus.sort_unstable();
+        cpus.dedup();
         Self {
             num_events: data.records.slice().len(),
             cur_event: 0,
@@ -481,12 +1200,18 @@ impl Status {
                 .trace_file
                 .as_ref()
                 .map(|f| f.to_string_lossy().to_string())
-                .unwrap_or("pfviz.json".into()),
+                .unwrap_or("pfviz".into()),
             current: "".into(),
             marker_a: None,
             marker_b: None,
             looping: true,
             paused: true,
+            reverse: false,
+            prompt: None,
+            indexed: None,
+            color_mode: cli.color,
+            threads: ThreadFilter::new(cli, data),
+            cpus,
         }
     }
 
@@ -503,18 +1228,19 @@ impl Status {
         data: &FaultData,
         hit_breakpoint: bool,
     ) {
-        if faults.len() == 0 {
+        if faults.is_empty() {
             return;
         }
         let fault = faults.last().unwrap();
         let off = humansize::format_size(fault.offset(), humansize::BINARY);
         let s = format!(
-            "(..{}) {:10}: {} to {} within {}{}",
+            "(..{}) {:10}: {} to {} within {} from {}{}",
             faults.len() - 1,
             idx,
-            fault.kind().to_string(),
+            fault.kind(),
             off,
             data.object_name(fault),
+            data.code_location(fault),
             if hit_breakpoint { "[BREAKPOINT]" } else { "" }
         );
         self.current = s;
@@ -553,7 +1279,17 @@ impl Widget for &Status {
             status_title += "(looping)";
         }
 
-        let playback_block = Block::default().borders(Borders::ALL).title(status_title).title_bottom("Help: (q) Quit; (Left/Right) Move Events; (Up/Down) Select File; (,/.) Set Marker A/B; (</>) Goto Marker A/B; (Space) Pause; (b) Set Breakpoint");
+        if self.reverse {
+            status_title += "(reverse)";
+        }
+
+        status_title += &format!("(colour: {})", self.color_mode);
+
+        if let Some(indexed) = self.indexed {
+            status_title += &format!("(indexing {:.0}%)", indexed * 100.0);
+        }
+
+        let playback_block = Block::default().borders(Borders::ALL).title(status_title).title_bottom("Help: (q) Quit; (Left/Right) Move Events; (Up/Down) Select File; (PgUp/PgDn) Scroll Files; (,/.) Set Marker A/B; (</>) Goto Marker A/B; (Space) Pause; (b) Set Breakpoint; (c) Colour Mode; (t/T) Move to/Pick Thread; (h) Hide/Dim Others; (v) Reverse; (m) Bar Mode; (p) Page Grid; (e/f) Event Log/Freeze; (s/g) Sort/Group Files; (:) Seek");
 
         let playback_inner = playback_block.inner(area);
 
@@ -563,6 +1299,8 @@ impl Widget for &Status {
                 Constraint::Length(1),
                 Constraint::Length(1),
                 Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
             ],
         );
         let playback_inner_splits = layout.split(playback_inner);
@@ -629,7 +1367,38 @@ impl Widget for &Status {
             self.cur_event, self.num_events
         ));
 
-        let log = Paragraph::new(self.current.as_str());
+        let log = match &self.prompt {
+            Some(prompt) => Paragraph::new(format!(":{}█", prompt)),
+            None => Paragraph::new(self.current.as_str()),
+        };
+        let legend = Line::from(
+            legend(self.color_mode, &self.cpus, &self.threads.tids)
+                .into_iter()
+                .flat_map(|(label, color)| {
+                    [
+                        Span::styled("█ ", Style::default().fg(color)),
+                        Span::raw(format!("{}  ", label)),
+                    ]
+                })
+                .collect::<Vec<_>>(),
+        );
+        let mut thread_spans = vec![Span::raw(if self.threads.hide {
+            "threads (hiding others): "
+        } else {
+            "threads (dimming others): "
+        })];
+        for (i, tid) in self.threads.tids.iter().enumerate() {
+            let mut style = Style::default();
+            if self.threads.picked.contains(tid) {
+                style = style.reversed();
+            }
+            if self.threads.cursor == Some(i) {
+                style = style.underlined();
+            }
+            thread_spans.push(Span::styled(tid.to_string(), style));
+            thread_spans.push(Span::raw(" "));
+        }
+        let threads = Line::from(thread_spans);
 
         playback_block.render(area, buf);
         progress_bar.render(prog_bar_splits[1], buf);
@@ -637,5 +1406,7 @@ impl Widget for &Status {
         time_progress_bar.render(time_bar_splits[1], buf);
         time_bar_text.render(time_bar_splits[0], buf);
         log.render(playback_inner_splits[2], buf);
+        legend.render(playback_inner_splits[3], buf);
+        threads.render(playback_inner_splits[4], buf);
     }
 }